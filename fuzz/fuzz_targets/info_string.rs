@@ -0,0 +1,22 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use mdbook_typst_highlight::info_string::{list, tokens, BlockSpec, TYPST_LANGS};
+
+fuzz_target!(|info: &str| {
+    let tokens = tokens(info);
+    assert!(tokens.iter().all(|token| !token.is_empty()));
+
+    match BlockSpec::parse(info) {
+        Some(spec) => {
+            assert_eq!(spec.lang, tokens[0]);
+            assert_eq!(spec.flags.len() + spec.attrs.len(), tokens.len() - 1);
+            for (key, value) in &spec.attrs {
+                assert!(spec.attr(key).is_some());
+                list(value);
+            }
+            spec.is_typst(&TYPST_LANGS.map(str::to_owned));
+        }
+        None => assert!(tokens.is_empty()),
+    }
+});
@@ -0,0 +1,295 @@
+//! Parsing of fenced code block info strings, like `typ-norender,query="<data>" noindex`
+
+/// What a fenced code block asks for
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct BlockSpec {
+    /// The first token, with its modifiers, like `typ-nopreamble`
+    pub lang: String,
+    /// Tokens without a value, like `noindex`
+    pub flags: Vec<String>,
+    /// `key=value` tokens in their order, with quotes removed from the value
+    pub attrs: Vec<(String, String)>,
+}
+
+impl BlockSpec {
+    /// Returns `None` for info strings without any tokens
+    pub fn parse(info: &str) -> Option<BlockSpec> {
+        let mut tokens = tokens(info).into_iter();
+        let mut spec = BlockSpec {
+            lang: tokens.next()?,
+            ..Default::default()
+        };
+
+        for token in tokens {
+            match token.split_once('=') {
+                Some((key, value)) => spec.attrs.push((key.to_owned(), value.to_owned())),
+                None => spec.flags.push(token),
+            }
+        }
+
+        Some(spec)
+    }
+
+    /// Whether the block is processed as Typst: the language is one of `languages`
+    /// (see `is_typst_lang`), and the block isn't marked `verbatim` to be left as it is
+    pub fn is_typst(&self, languages: &[String]) -> bool {
+        is_typst_lang(&self.lang, languages) && !self.modifier("verbatim")
+    }
+
+    pub fn flag(&self, name: &str) -> bool {
+        self.flags.iter().any(|flag| flag == name)
+    }
+
+    /// Options like `nopreamble` can be part of the language (`typ-nopreamble`) or a flag
+    pub fn modifier(&self, name: &str) -> bool {
+        self.lang
+            .split('-')
+            .skip(1)
+            .any(|modifier| modifier == name)
+            || self.flag(name)
+    }
+
+    /// Value of the first `key=value` attribute with this key
+    pub fn attr(&self, key: &str) -> Option<&str> {
+        self.attrs
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Languages highlighted (and rendered) as Typst, unless the `languages` setting is given
+pub const TYPST_LANGS: [&str; 4] = ["typ", "typst", "typc", "typm"];
+
+/// Whether the language, without its `-modifiers`, is one of `languages`, like `TYPST_LANGS`.
+/// `typescript` or `prototype` are not. Languages may contain `-` themselves, like
+/// `typst-example`, then `typst-example-norender` is that language with a modifier.
+pub fn is_typst_lang(lang: &str, languages: &[String]) -> bool {
+    languages.iter().any(|name| {
+        lang.strip_prefix(name.as_str())
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('-'))
+    })
+}
+
+/// Splits an info string into tokens.
+/// Tokens are separated by commas or whitespace, double quotes and `[...]` group and `\` escapes.
+pub fn tokens(info: &str) -> Vec<String> {
+    let mut tokens = vec![];
+    let mut token = String::new();
+    let mut quoted = false;
+    // Depth of `[...]` lists, which are kept as a single token
+    let mut depth = 0usize;
+    let mut chars = info.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => token.extend(chars.next()),
+            '"' if depth > 0 => {
+                quoted = !quoted;
+                token.push(c)
+            }
+            '"' => quoted = !quoted,
+            '[' if !quoted => {
+                depth += 1;
+                token.push(c)
+            }
+            ']' if !quoted => {
+                depth = depth.saturating_sub(1);
+                token.push(c)
+            }
+            c if !quoted && depth == 0 && (c == ',' || c.is_whitespace()) => {
+                if !token.is_empty() {
+                    tokens.push(std::mem::take(&mut token));
+                }
+            }
+            c => token.push(c),
+        }
+    }
+    if !token.is_empty() {
+        tokens.push(token);
+    }
+
+    tokens
+}
+
+/// Items of a list attribute value like `[a, "b c"]`
+pub fn list(value: &str) -> Vec<String> {
+    let value = value.strip_prefix('[').unwrap_or(value);
+    let value = value.strip_suffix(']').unwrap_or(value);
+    value
+        .split(',')
+        .map(|item| item.trim().trim_matches('"').to_owned())
+        .filter(|item| !item.is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn langs() -> Vec<String> {
+        TYPST_LANGS.map(str::to_owned).to_vec()
+    }
+
+    #[test]
+    fn tokens_are_split_by_commas_and_whitespace() {
+        assert_eq!(
+            tokens("typ,norender  noindex"),
+            ["typ", "norender", "noindex"]
+        );
+        assert_eq!(tokens(",, typ ,\t,"), ["typ"]);
+        assert!(tokens("").is_empty());
+        assert!(tokens(" , ").is_empty());
+    }
+
+    #[test]
+    fn quotes_group_and_are_removed() {
+        assert_eq!(tokens(r#"typ caption="a, b""#), ["typ", "caption=a, b"]);
+        // Empty quotes are no token
+        assert_eq!(tokens(r#"typ """#), ["typ"]);
+        // An unterminated quote takes the rest
+        assert_eq!(tokens(r#"typ caption="a, b c"#), ["typ", "caption=a, b c"]);
+    }
+
+    #[test]
+    fn backslashes_escape() {
+        assert_eq!(tokens(r"typ a\,b"), ["typ", "a,b"]);
+        assert_eq!(
+            tokens(r#"typ caption="a \" b""#),
+            ["typ", r#"caption=a " b"#]
+        );
+        // A trailing backslash escapes nothing
+        assert_eq!(tokens(r"typ a\"), ["typ", "a"]);
+        assert_eq!(tokens(r"\"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn brackets_group_lists() {
+        assert_eq!(
+            tokens(r#"typ themes=[a, "b c"] noindex"#),
+            ["typ", r#"themes=[a, "b c"]"#, "noindex"]
+        );
+        // Quotes inside a list are kept, and brackets inside them don't count
+        assert_eq!(tokens(r#"typ x=["a]", b]"#), ["typ", r#"x=["a]", b]"#]);
+        // An unclosed bracket takes the rest, a stray one closes nothing
+        assert_eq!(tokens("typ themes=[a, b"), ["typ", "themes=[a, b"]);
+        assert_eq!(tokens("typ a] b"), ["typ", "a]", "b"]);
+    }
+
+    #[test]
+    fn spec_of_flags_and_attrs() {
+        let spec = BlockSpec::parse(r#"typ noindex caption="a, b" query=<a=b>"#).unwrap();
+        assert_eq!(spec.lang, "typ");
+        assert_eq!(spec.flags, ["noindex"]);
+        assert_eq!(spec.attr("caption"), Some("a, b"));
+        assert_eq!(spec.attr("query"), Some("<a=b>"));
+        assert_eq!(spec.attr("root"), None);
+        assert!(spec.flag("noindex"));
+        assert!(!spec.flag("caption"));
+
+        assert_eq!(BlockSpec::parse(""), None);
+        assert_eq!(BlockSpec::parse(" ,"), None);
+    }
+
+    #[test]
+    fn modifiers_in_the_language_or_as_flags() {
+        for info in ["typ-norender", "typ norender", "typ,norender"] {
+            let spec = BlockSpec::parse(info).unwrap();
+            assert!(spec.modifier("norender"), "{info}");
+            assert!(!spec.modifier("nopreamble"), "{info}");
+            assert!(spec.is_typst(&langs()), "{info}");
+        }
+        assert_eq!(
+            BlockSpec::parse("typ-norender").unwrap().lang,
+            "typ-norender"
+        );
+        assert!(BlockSpec::parse("typ norender").unwrap().flag("norender"));
+        // Only the part after the language is modifiers
+        assert!(!BlockSpec::parse("norender").unwrap().modifier("norender"));
+    }
+
+    #[test]
+    fn verbatim_blocks_are_not_typst() {
+        for info in ["typ,verbatim", "typ-verbatim", "typst verbatim"] {
+            assert!(
+                !BlockSpec::parse(info).unwrap().is_typst(&langs()),
+                "{info}"
+            );
+        }
+    }
+
+    #[test]
+    fn typst_languages() {
+        for lang in [
+            "typ",
+            "typst",
+            "typc",
+            "typm",
+            "typ-norender",
+            "typst-nopreamble",
+        ] {
+            assert!(is_typst_lang(lang, &langs()), "{lang}");
+        }
+        for lang in ["typescript", "prototype", "ty", "", "rust", "typ_x"] {
+            assert!(!is_typst_lang(lang, &langs()), "{lang}");
+        }
+
+        let custom = ["typst-example".to_owned()];
+        assert!(is_typst_lang("typst-example", &custom));
+        assert!(is_typst_lang("typst-example-norender", &custom));
+        assert!(!is_typst_lang("typst", &custom));
+        assert!(!is_typst_lang("typst-examples", &custom));
+    }
+
+    #[test]
+    fn list_items() {
+        assert_eq!(list(r#"[a, "b c"]"#), ["a", "b c"]);
+        assert_eq!(list("a"), ["a"]);
+        assert_eq!(list("[a,,b, ]"), ["a", "b"]);
+        assert!(list("[]").is_empty());
+        assert!(list("").is_empty());
+    }
+
+    #[test]
+    fn fences_of_similar_languages() {
+        let is_typst = |info| BlockSpec::parse(info).unwrap().is_typst(&langs());
+        assert!(is_typst("typ"));
+        assert!(is_typst("typst render"));
+        assert!(is_typst("typ,norender"));
+        assert!(!is_typst("typescript"));
+        assert!(!is_typst("type"));
+        assert!(!is_typst("typescript typ"));
+    }
+
+    #[test]
+    fn representative_fences() {
+        let spec = BlockSpec::parse("typ,norender").unwrap();
+        assert_eq!(spec.lang, "typ");
+        assert_eq!(spec.flags, ["norender"]);
+
+        let spec = BlockSpec::parse("typst nopreamble").unwrap();
+        assert!(spec.modifier("nopreamble"));
+        assert!(spec.is_typst(&langs()));
+
+        let spec = BlockSpec::parse(
+            r#"typ,render,caption="A plot, with \"quotes\"",width=900pt preamble=extra.typ"#,
+        )
+        .unwrap();
+        assert_eq!(spec.lang, "typ");
+        assert_eq!(spec.flags, ["render"]);
+        assert_eq!(
+            spec.attrs,
+            [
+                ("caption".to_owned(), r#"A plot, with "quotes""#.to_owned()),
+                ("width".to_owned(), "900pt".to_owned()),
+                ("preamble".to_owned(), "extra.typ".to_owned()),
+            ]
+        );
+
+        // The first of repeated attributes counts, and values may contain `=`
+        let spec = BlockSpec::parse(r#"typ-norender format=png format=svg query="<a>=1""#).unwrap();
+        assert!(spec.modifier("norender"));
+        assert_eq!(spec.attr("format"), Some("png"));
+        assert_eq!(spec.attr("query"), Some("<a>=1"));
+    }
+}
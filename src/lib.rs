@@ -1,409 +1,4343 @@
+pub mod info_string;
+mod library;
+mod options;
+mod remote;
+#[cfg(feature = "watch")]
+pub mod watch;
+
 use async_process::Command;
-use futures::future::join_all;
+use base64::prelude::*;
+use futures::future::{join_all, try_join_all, LocalBoxFuture};
+use futures::{FutureExt, TryFutureExt};
+use rayon::prelude::*;
 use sha2::{Digest, Sha256};
+use std::cell::Cell;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::fs::{self, File};
 use std::future::Future;
-use std::io::Write;
 use std::iter;
-use std::path::PathBuf;
+use std::num::NonZeroUsize;
+use std::path::{Component, Path, PathBuf};
 use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Semaphore;
 
 use anyhow::anyhow;
+use info_string::BlockSpec;
+use itertools::Itertools;
 use lazy_static::lazy_static;
-use mdbook::book::Book;
+use log::{error, info, log, warn, Level};
+use lru::LruCache;
+use mdbook::book::{Book, Chapter};
 use mdbook::errors::{Error, Result};
 use mdbook::preprocess::{Preprocessor, PreprocessorContext};
 use mdbook::utils::new_cmark_parser;
-use mdbook::BookItem;
+use mdbook::{BookItem, Config};
+use options::Options;
 use pulldown_cmark::{CodeBlockKind, CowStr, Event, Tag};
 use pulldown_cmark_to_cmark::cmark;
-use syntect::highlighting::Color;
-use syntect::parsing::SyntaxSet;
+use serde::Serialize;
+use syntect::highlighting::{Color, FontStyle, Style};
+use syntect::parsing::{
+    ParseState, ScopeStack, SyntaxDefinition, SyntaxReference, SyntaxSet, SyntaxSetBuilder,
+};
+use sysinfo::System;
 
-use syntect::easy::HighlightLines;
+use syntect::easy::{HighlightLines, ScopeRegionIterator};
 use syntect::highlighting::{Theme, ThemeSet};
-use syntect::html::{
-    append_highlighted_html_for_styled_line, styled_line_to_highlighted_html, IncludeBackground,
-};
+use syntect::html::{css_for_theme_with_class_style, ClassStyle, ClassedHTMLGenerator};
 use syntect::util::LinesWithEndings;
 
+/// Preamble of blocks, unless `preamble` or `preamble_file` is set
 static PREAMBLE: &str = "
 #set page(height: auto, width: 400pt, margin: 0.5cm)
 ";
 
+/// Rendered inline math is cropped to the formula, so it can sit in a line of text
+static INLINE_PREAMBLE: &str = "
+#set page(height: auto, width: auto, margin: 0pt)
+";
+
+/// Rendered inline math is also padded to have the baseline in the middle of the image,
+/// so it can be aligned to the baseline of the text around it.
+/// The baseline is found with a label placed after the formula, which takes Typst a second layout pass.
+static INLINE_MATH_PREAMBLE: &str = "
+#set page(height: auto, width: auto, margin: 0pt)
+#show math.equation.where(block: false): it => context {
+  let found = query(<typst-baseline>)
+  let ascent = if found.len() > 0 { found.first().location().position().y } else { 0pt }
+  let depth = calc.max(ascent, measure(it).height - ascent)
+  box(height: 2 * depth, baseline: depth) + it + box[#metadata(none)<typst-baseline>]
+}
+";
+
+/// Prefix of inline code that is math, like `` `typm: x^2` ``, besides `math_delimiter`
+static MATH_PREFIX: &str = "typm: ";
+
+/// Prefix of inline code that gets a rendered tooltip with `inline_preview`
+static PREVIEW_PREFIX: &str = "typ-preview: ";
+
+/// Theme used without a `theme` setting
+static DEFAULT_THEME: &str = "Solarized (dark)";
+
+/// A built-in syntect theme whose foreground is replaced by mdBook's text color
+fn book_theme(name: &str) -> Result<Theme> {
+    let mut theme = THEMES
+        .themes
+        .get(name)
+        .ok_or_else(|| {
+            anyhow!(
+                "Unknown theme \"{}\", available are: {}",
+                name,
+                THEMES.themes.keys().join(", ")
+            )
+        })?
+        .clone();
+    theme.settings.foreground = Some(FOREGROUND);
+
+    Ok(theme)
+}
+
+/// Stands for mdBook's text color in themes, highlighted as `var(--fg)`.
+/// The probality that it clashes with a real theme color is ≈ 1/(2⁸)⁴ ≈ 1/(2³²),
+/// in fact much less, very few people use alphas
+const FOREGROUND: Color = Color {
+    r: 27,
+    g: 223,
+    b: 51,
+    a: 99,
+};
+
+/// Theme colors written as CSS variables, by default only the foreground sentinel
+fn default_color_vars() -> Vec<(Color, String)> {
+    vec![(FOREGROUND, "var(--fg)".to_owned())]
+}
+
+/// Parses `#rrggbb` or `#rrggbbaa`
+fn parse_color(hex: &str) -> Option<Color> {
+    let hex = hex.strip_prefix('#')?;
+    if !(hex.len() == 6 || hex.len() == 8) || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).unwrap();
+    Some(Color {
+        r: channel(0),
+        g: channel(2),
+        b: channel(4),
+        a: if hex.len() == 8 { channel(6) } else { 0xFF },
+    })
+}
+
+/// CSS value of a color: its variable from `color_vars` or its hex like syntect writes it
+fn css_color(color: Color, color_vars: &[(Color, String)]) -> String {
+    if let Some((_, var)) = color_vars.iter().find(|(c, _)| *c == color) {
+        return var.clone();
+    }
+    if color.a == 0xFF {
+        format!("#{:02x}{:02x}{:02x}", color.r, color.g, color.b)
+    } else {
+        format!(
+            "#{:02x}{:02x}{:02x}{:02x}",
+            color.r, color.g, color.b, color.a
+        )
+    }
+}
+
+/// Like syntect's `append_highlighted_html_for_styled_line` without background,
+/// but colors from `color_vars` are written as their CSS variables
+fn append_styled_html(
+    regions: &[(Style, &str)],
+    color_vars: &[(Color, String)],
+    html: &mut String,
+) {
+    let mut prev: Option<(Style, String)> = None;
+    for &(style, text) in regions {
+        match &mut prev {
+            Some((prev_style, prev_text))
+                if *prev_style == style
+                    || (prev_style.background == style.background && text.trim().is_empty()) =>
+            {
+                prev_text.push_str(text)
+            }
+            _ => {
+                if let Some((style, text)) = prev.take() {
+                    push_styled_span(style, &text, color_vars, html);
+                }
+                prev = Some((style, text.to_owned()));
+            }
+        }
+    }
+    if let Some((style, text)) = prev {
+        push_styled_span(style, &text, color_vars, html);
+    }
+}
+
+fn push_styled_span(style: Style, text: &str, color_vars: &[(Color, String)], html: &mut String) {
+    html.push_str("<span style=\"");
+    if style.font_style.contains(FontStyle::UNDERLINE) {
+        html.push_str("text-decoration:underline;");
+    }
+    if style.font_style.contains(FontStyle::BOLD) {
+        html.push_str("font-weight:bold;");
+    }
+    if style.font_style.contains(FontStyle::ITALIC) {
+        html.push_str("font-style:italic;");
+    }
+    html.push_str("color:");
+    html.push_str(&css_color(style.foreground, color_vars));
+    html.push_str(";\">");
+    html.push_str(&escape_html(text).replace('\'', "&#39;"));
+    html.push_str("</span>");
+}
+
+/// Name of the book-wide context file, placed next to the generated sources
+static CONTEXT_FILE: &str = "typst-context.typ";
+
 lazy_static! {
     /// This is an example for using doc comment attributes
-    static ref THEME: Theme = {
-        let ts = ThemeSet::load_defaults();
-        let mut theme = ts.themes["Solarized (dark)"].clone();
-        theme.settings.foreground = Some(Color {
-            r: 27,
-            g: 223,
-            b: 51,
-            a: 99,
-        });
-        // The probality that the hack will break when you are writing colors is ≈ 1/(2⁸)⁴ ≈ 1/(2³²)
-        // In fact much less, very few people use alphas
+    static ref THEMES: ThemeSet = ThemeSet::load_defaults();
 
-        theme
-    };
+    static ref SYNTAX: SyntaxSet = syntax_set_builder().build();
+}
 
-    static ref SYNTAX: SyntaxSet = {
-        let typst_syntax = syntect::parsing::syntax_definition::SyntaxDefinition::load_from_str(
-            include_str!("../res/Typst.sublime-syntax"),
-            true,
-            None,
-        ).expect("Syntax data was corrupted");
+/// The bundled Typst grammar
+pub fn typst_syntax_definition() -> SyntaxDefinition {
+    SyntaxDefinition::load_from_str(include_str!("../res/Typst.sublime-syntax"), true, None)
+        .expect("Syntax data was corrupted")
+}
 
-        let mut syntax = SyntaxSet::load_defaults_nonewlines().into_builder();
-        syntax.add(typst_syntax);
-        syntax.build()
-    };
+/// The grammars used for highlighting: syntect's defaults, with the Typst grammar added last.
+/// More grammars can be added before building the set, e.g. for tools that highlight
+/// other languages next to Typst.
+pub fn syntax_set_builder() -> SyntaxSetBuilder {
+    let mut syntax = SyntaxSet::load_defaults_nonewlines().into_builder();
+    syntax.add(typst_syntax_definition());
+    syntax
+}
+
+/// The grammars the preprocessor highlights with, as built by `syntax_set_builder()`
+pub fn typst_syntax_set() -> &'static SyntaxSet {
+    &SYNTAX
+}
+
+/// Highlights Typst source like the preprocessor does, with colors as inline styles.
+///
+/// Inline code becomes `<code class="hljs">…</code>`, a block becomes
+/// `<pre style="margin: 0"><code class="language-typ hljs">…</code></pre>` followed by a line
+/// break. Inside, every token is a `<span style="color:…">`. A final line ending is dropped.
+pub fn highlight_typst(src: &str, inline: bool, theme: &Theme) -> String {
+    highlight(
+        src.to_owned().into(),
+        typst_syntax(),
+        theme,
+        inline,
+        None,
+        &default_color_vars(),
+        "",
+    )
 }
 
 struct PreprocessSettings {
+    /// The options as they are written, for those that are used without changes.
+    /// Those with defaults, or read from files, are the fields below.
+    options: Options,
+    /// Unless `disable_inline`
     highlight_inline: bool,
-    typst_default: bool,
+    /// `render`, unless `MDBOOK_TYPST_RENDER` overrides it
     render: bool,
-    warn_not_specified: bool,
+    min_free_memory: Option<u64>,
+    max_inline_length: Option<usize>,
+    /// Content of `context_file`
+    context: Option<String>,
+    debug_hash: bool,
+    package_path: Option<PathBuf>,
+    /// Where Typst keeps downloaded packages, like `@preview` ones, from `package_cache_path`
+    package_cache_path: Option<PathBuf>,
+    css_vars: Vec<(String, String)>,
+    book_root: PathBuf,
+    /// Canonical paths that blocks may use as their root
+    allowed_roots: Vec<PathBuf>,
+    math_delimiter: String,
+    /// Emit classes instead of inline colors, with the theme as a stylesheet
+    class_style: Option<ClassStyle>,
+    /// Highlighted inline code by syntax name and source, if caching is enabled
+    inline_cache: Option<Mutex<LruCache<(String, String), String>>>,
+    /// Where to write standalone HTML of every rendered block
+    fragments_dir: Option<PathBuf>,
+    /// How the pages of multi-page blocks are arranged
+    pages_layout: PagesLayout,
+    /// Render inline math instead of only highlighting it
+    render_inline: bool,
+    /// Passed to every block as `sys.inputs.seed`
+    seed: Option<String>,
+    /// Class of inline math left for a script in the browser to render
+    client_math_class: Option<String>,
+    /// Render `typ-preview:` inline code into a tooltip
+    inline_preview: bool,
+    /// Images up to this size are put into the page instead of being linked
+    embed_max_bytes: Option<u64>,
+    /// When compilations stop being started, from `render_time_limit`
+    render_deadline: Option<Instant>,
+    /// Service that compiles blocks instead of the local Typst
+    remote: Option<remote::Remote>,
+    /// Put all images into the pages, so they work as single files
+    offline: bool,
+    /// Caption text with `{caption}` and `{number}` placeholders
+    caption_template: String,
+    /// Number of a captioned block with `{chapter}` and `{n}` placeholders
+    figure_number: String,
+    /// Start counting captioned blocks from 1 in every chapter
+    figure_number_reset: bool,
+    /// Page listing all figures, relative to the book source
+    gallery: Option<PathBuf>,
+    /// Highlighting theme, from `theme`
+    theme: Theme,
+    /// Threads for highlighting the whole book ahead, see `prehighlight`
+    highlight_threads: usize,
+    /// Results of `prehighlight`
+    prehighlighted: HashMap<HighlightKey, String>,
+    /// Highlighting theme for mdBook's dark themes, with `theme` as the light one
+    theme_dark: Option<Theme>,
+    /// Preamble of blocks without a chapter preamble, from `preamble` or `preamble_file`
+    preamble: String,
+    format: ImageFormat,
+    /// ICC profile embedded into PNG images, from `color_profile`
+    color_profile: Option<Arc<[u8]>>,
+    /// The Typst executable, `typst` from `PATH` by default
+    typst_path: String,
+    /// Limits the number of Typst processes running at once, see `max_concurrency`
+    compile_slots: Arc<Semaphore>,
+    /// Theme colors written as CSS variables, the foreground sentinel and `color_var_map`
+    color_vars: Vec<(Color, String)>,
+    /// Extra arguments of every Typst run, from `typst_args` and `inputs`
+    typst_args: Vec<String>,
+    /// Time a single compilation may take before it's stopped, from `render_timeout_secs`
+    render_timeout: Option<Duration>,
+    /// Directory next to a chapter with its rendered images, from `img_dir`
+    img_dir: String,
+    /// Directory next to a chapter with the sources of its blocks, from `src_dir`
+    src_dir: String,
+    /// Root of blocks without `root=`, instead of the book source directory
+    typst_root: Option<PathBuf>,
+    /// Put before the `hljs` and `typ` classes of highlighted code, from `class_prefix`
+    class_prefix: String,
+    /// Code and render of blocks are next to each other, from `layout = "side-by-side"`
+    side_by_side: bool,
+    /// Whether `typm: ` inline code is math, like code between `math_delimiter`
+    math_prefix: bool,
+    /// Default of `--typst-img-maxwidth`, from `image_max_width`
+    image_max_width: String,
+    /// `text-align` of rendered images, from `image_align`
+    image_align: String,
+    /// Languages of Typst blocks, from `languages`
+    languages: Vec<String>,
+    /// Compiles blocks in this process instead of with the Typst CLI, from `backend`
+    library: Option<library::Library>,
+    /// Directories given to Typst with `--font-path`
+    font_paths: Vec<PathBuf>,
+    /// Page color of the dark variants of blocks, rendered when `render_dark` is set
+    dark_page_fill: Option<String>,
+    /// Shared with the compilations, which outlive the borrows of the settings
+    state: Arc<BuildState>,
 }
 
-pub struct TypstHighlight;
-
-fn get_setting(preprocessor: Option<&toml::map::Map<String, toml::Value>>, name: &str) -> bool {
-    preprocessor
-        .and_then(|typst_cfg| {
-            typst_cfg.get(name).map(|v| {
-                v.as_bool()
-                    .expect(&("Incorrect argument at".to_owned() + name))
-            })
-        })
-        .unwrap_or(false)
+/// Format of rendered images, from `format`
+#[derive(Clone, Copy, PartialEq)]
+enum ImageFormat {
+    Svg,
+    Png,
 }
 
-impl Preprocessor for TypstHighlight {
-    fn name(&self) -> &str {
-        "typst-highlight"
+impl ImageFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            ImageFormat::Svg => "svg",
+            ImageFormat::Png => "png",
+        }
     }
+}
 
-    fn run(&self, ctx: &PreprocessorContext, mut book: Book) -> Result<Book, Error> {
-        let prep = ctx.config.get_preprocessor(self.name());
+#[derive(Clone, Copy, PartialEq)]
+enum PagesLayout {
+    Stacked,
+    Row,
+    Grid,
+}
 
-        let highlight_inline = !get_setting(prep, "disable_inline");
-        let typst_default = get_setting(prep, "typst_default");
-        let render = get_setting(prep, "render");
-        let warn_not_specified = get_setting(prep, "warn_not_specified");
+impl PreprocessSettings {
+    /// The settings of a build of the book at `root`. Fails on invalid options, and on
+    /// options that can't be used together.
+    fn new(options: Options, root: &Path, config: &Config, renderer: &str) -> Result<Self> {
+        let render_override = render_override()?;
+        // Options that need rendering are turned off with it, instead of failing the build
+        let rendering_off = render_override == Some(false);
+        let render_inline = options.render_inline && !rendering_off;
+        let client_math_class = options.client_math.then(|| {
+            options
+                .client_math_class
+                .as_deref()
+                .unwrap_or("typst-math")
+                .to_owned()
+        });
+        let package_path = options.package_path.as_deref().map(|p| root.join(p));
+        let package_cache_path = options.package_cache_path.as_deref().map(|p| root.join(p));
+        let font_paths = font_paths(&options, root, config);
+        let class_style = class_style(&options);
+        let (theme, theme_dark) = themes(&options, root)?;
+        let (img_dir, src_dir) = cache_dirs(&options)?;
+        let format = image_format(&options)?;
+        check_inline_languages(&options)?;
+        let inline_cache_size = options.inline_cache_size.unwrap_or(1024);
 
         let settings = PreprocessSettings {
-            highlight_inline,
-            typst_default,
-            render,
-            warn_not_specified,
+            highlight_inline: !options.disable_inline,
+            render: render_override.unwrap_or(options.render),
+            min_free_memory: options
+                .min_free_memory
+                .map(|mb| mb.saturating_mul(1024 * 1024)),
+            max_inline_length: options.max_inline_length,
+            context: options
+                .context_file
+                .as_deref()
+                .map(|file| {
+                    fs::read_to_string(root.join(file))
+                        .map_err(|err| anyhow!("Can't read context file {}: {}", file, err))
+                })
+                .transpose()?,
+            debug_hash: options.debug_hash || std::env::var_os("MDBOOK_TYPST_DEBUG_HASH").is_some(),
+            library: library(&options, &font_paths, &package_path, &package_cache_path)?,
+            package_path,
+            package_cache_path,
+            css_vars: css_vars(&options),
+            book_root: root.to_path_buf(),
+            allowed_roots: allowed_roots(&options, root)?,
+            math_delimiter: options.math_delimiter.as_deref().unwrap_or("$").to_owned(),
+            class_style,
+            inline_cache: NonZeroUsize::new(inline_cache_size)
+                .map(|size| Mutex::new(LruCache::new(size))),
+            fragments_dir: options.fragments_dir.as_deref().map(|p| root.join(p)),
+            pages_layout: pages_layout(&options)?,
+            render_inline,
+            seed: options.seed.as_ref().map(toml_string),
+            // Without anything to do with math, `typm: ` code is highlighted like other prefixes
+            math_prefix: render_inline || client_math_class.is_some(),
+            client_math_class,
+            inline_preview: options.inline_preview && !rendering_off,
+            embed_max_bytes: options.embed_max_bytes,
+            render_deadline: options
+                .render_time_limit
+                .map(|secs| Instant::now() + Duration::from_secs(secs)),
+            remote: remote(&options)?,
+            // EPUB packers only take files they know about, so everything is put into the pages
+            offline: options.offline || renderer == "epub",
+            caption_template: options
+                .caption_template
+                .as_deref()
+                .unwrap_or("{caption}")
+                .to_owned(),
+            figure_number: options.figure_number.as_deref().unwrap_or("{n}").to_owned(),
+            figure_number_reset: !options.continuous_figure_numbers,
+            gallery: options.gallery.as_deref().map(PathBuf::from),
+            theme,
+            highlight_threads: match options.highlight_threads {
+                Some(0) => return Err(anyhow!("highlight_threads must be at least 1")),
+                threads => threads.unwrap_or(1),
+            },
+            prehighlighted: HashMap::new(),
+            theme_dark,
+            preamble: preamble(&options, root)?,
+            format,
+            color_profile: color_profile(&options, root)?.filter(|_| format == ImageFormat::Png),
+            typst_path: options.typst_path.as_deref().unwrap_or("typst").to_owned(),
+            compile_slots: compile_slots(&options)?,
+            color_vars: color_vars(&options)?,
+            typst_args: typst_args(&options),
+            render_timeout: options.render_timeout_secs.map(Duration::from_secs),
+            img_dir,
+            src_dir,
+            typst_root: options
+                .typst_root
+                .as_deref()
+                .map(|dir| {
+                    root.join(dir)
+                        .canonicalize()
+                        .map_err(|err| anyhow!("Invalid typst_root {}: {}", dir, err))
+                })
+                .transpose()?,
+            class_prefix: options.class_prefix.clone().unwrap_or_default(),
+            side_by_side: side_by_side(&options)?,
+            image_max_width: image_max_width(&options)?,
+            image_align: image_align(&options)?,
+            languages: languages(&options)?,
+            font_paths,
+            dark_page_fill: dark_page_fill(&options)?,
+            state: Arc::default(),
+            options,
         };
+        settings.check_conflicts()?;
+        Ok(settings)
+    }
 
-        book.sections.iter_mut().try_for_each(|section| {
-            let mut build_dir = ctx.root.clone();
-            build_dir.push(&ctx.config.book.src);
+    /// Fails on options that can't be used together, or that need others
+    fn check_conflicts(&self) -> Result<()> {
+        let options = &self.options;
+        if self.render_inline {
+            if !self.render {
+                return Err(anyhow!("render_inline needs render = true"));
+            }
+            if !self.highlight_inline {
+                return Err(anyhow!(
+                    "render_inline can't be used with disable_inline, as inline code is skipped"
+                ));
+            }
+        }
+        if self.client_math_class.is_some() {
+            if self.render_inline {
+                return Err(anyhow!(
+                    "client_math and render_inline both render inline math, choose one"
+                ));
+            }
+            if !self.highlight_inline {
+                return Err(anyhow!(
+                    "client_math needs inline math, which disable_inline turns off"
+                ));
+            }
+        }
+        if self.inline_preview && (!self.render || !self.highlight_inline) {
+            return Err(anyhow!(
+                "inline_preview needs render = true and can't be used with disable_inline"
+            ));
+        }
+        if self.theme_dark.is_some() && self.class_style.is_some() {
+            return Err(anyhow!(
+                "theme_light and theme_dark can't be used together with classed"
+            ));
+        }
 
-            process_chapter(section, &settings, &build_dir)
-        })?;
+        if options.ppi.is_some() && self.format != ImageFormat::Png {
+            return Err(anyhow!("ppi only applies to format = \"png\""));
+        }
+        if self.format == ImageFormat::Png
+            && (options.experimental_dark_svg || self.remote.is_some())
+        {
+            return Err(anyhow!(
+                "experimental_dark_svg and remote_url only work with SVG images"
+            ));
+        }
+        if options.inline_svg && self.format == ImageFormat::Png {
+            return Err(anyhow!("inline_svg only works with SVG images"));
+        }
+        if self.remote.is_some() {
+            // Only the source is sent
+            if !self.typst_args.is_empty() {
+                return Err(anyhow!("typst_args and inputs don't work with remote_url"));
+            }
+            if self.seed.is_some() {
+                return Err(anyhow!("seed doesn't work with remote_url"));
+            }
+        }
+        if !options.keep_typ_source && options.source_link {
+            return Err(anyhow!(
+                "source_link needs the sources, so keep_typ_source can't be false"
+            ));
+        }
 
-        Ok(book)
+        if self.library.is_some() {
+            if self.remote.is_some() {
+                return Err(anyhow!(
+                    "backend = \"library\" can't be used with remote_url"
+                ));
+            }
+            if self.format == ImageFormat::Png {
+                return Err(anyhow!("backend = \"library\" only renders SVG images"));
+            }
+            if !self.typst_args.is_empty() {
+                return Err(anyhow!(
+                    "typst_args and inputs don't work with backend = \"library\""
+                ));
+            }
+        }
+
+        if self.dark_page_fill.is_some() {
+            if !self.render {
+                return Err(anyhow!("render_dark needs render = true"));
+            }
+            if options.experimental_dark_svg {
+                return Err(anyhow!(
+                    "render_dark and experimental_dark_svg both make dark images, choose one"
+                ));
+            }
+        }
+
+        Ok(())
     }
 
-    fn supports_renderer(&self, renderer: &str) -> bool {
-        renderer == "html"
+    /// Resolves a per-block root, which must be one of `allowed_roots`
+    fn allowed_root(&self, root: &str) -> Result<PathBuf> {
+        let path = self
+            .book_root
+            .join(root)
+            .canonicalize()
+            .map_err(|err| anyhow!("Invalid root {}: {}", root, err))?;
+
+        if self.allowed_roots.contains(&path) {
+            Ok(path)
+        } else {
+            Err(anyhow!("Root {} is not listed in allowed_roots", root))
+        }
     }
 }
 
-fn process_chapter(
-    section: &mut BookItem,
-    settings: &PreprocessSettings,
-    build_dir: &PathBuf,
-) -> Result<()> {
-    if let BookItem::Chapter(chapter) = section {
-        chapter
-            .sub_items
-            .iter_mut()
-            .try_for_each(|section| process_chapter(section, settings, build_dir))?;
-
-        let events = new_cmark_parser(&chapter.content, false);
-        let mut new_events = Vec::new();
-        let mut codeblock_text = None;
-
-        let mut chapter_path = build_dir.clone();
-        if let Some(p) = chapter.path.as_ref().and_then(|p| p.parent()) {
-            chapter_path.push(p)
-        };
+pub struct TypstHighlight;
 
-        let mut compile_errors = vec![];
+/// A rendered block, as listed in the manifest
+#[derive(Serialize)]
+struct Figure {
+    chapter: String,
+    /// URL of the chapter page, relative to the book root
+    #[serde(skip_serializing_if = "Option::is_none")]
+    page: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    caption: Option<String>,
+    /// Description of the images, from `alt=`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    alt: Option<String>,
+    /// Whether this is the first figure of its chapter, to be used as its preview
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    og_image: bool,
+    hash: String,
+    /// URLs of the images, relative to the book root
+    images: Vec<String>,
+    /// URLs of the dark variants of `images`, from `render_dark`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    dark_images: Vec<String>,
+    /// Those of `images` that are put into the page, so the page doesn't link them
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    embedded: Vec<String>,
+    /// Files the block reads, relative to the book source
+    dependencies: Vec<PathBuf>,
+}
 
-        for event in events {
-            match event {
-                Event::Start(tag) => {
-                    let lang = get_lang(&tag, settings, None);
+impl Figure {
+    fn new(chapter: &Chapter, hash: &str, deps: &[PathBuf], build_dir: &Path) -> Self {
+        Figure {
+            chapter: chapter.name.clone(),
+            page: chapter.path.as_ref().map(|path| {
+                // Also on Windows, as it's a URL
+                path.with_extension("html")
+                    .components()
+                    .map(|c| c.as_os_str().to_string_lossy())
+                    .join("/")
+            }),
+            caption: None,
+            alt: None,
+            og_image: false,
+            hash: hash.to_owned(),
+            images: vec![],
+            dark_images: vec![],
+            embedded: vec![],
+            dependencies: deps
+                .iter()
+                .map(|dep| dep.strip_prefix(build_dir).unwrap_or(dep).to_path_buf())
+                .collect(),
+        }
+    }
+}
 
-                    if let Some(lang) = lang {
-                        if is_typst_codeblock(lang) {
-                            codeblock_text = Some(String::new())
-                        } else {
-                            new_events.push(Event::Start(tag))
-                        }
-                    } else {
-                        new_events.push(Event::Start(tag))
-                    }
-                }
-                Event::End(tag) => {
-                    let lang = get_lang(&tag, settings, Some(&chapter.name));
-
-                    if let Some(lang) = lang {
-                        if is_typst_codeblock(lang) {
-                            let text = codeblock_text.ok_or(anyhow!(
-                                "Typst codeblock wasn't created: chapter {}.
-                                    Data collected: {:?}",
-                                chapter.name,
-                                new_events
-                            ))?;
-
-                            let mut html = highlight(text.clone().into(), false);
-
-                            if settings.render && !lang.contains("norender") {
-                                let (file, err) = render_block(
-                                    text,
-                                    chapter_path.clone(),
-                                    build_dir.clone(),
-                                    chapter.name.clone(),
-                                    !lang.contains("nopreamble"),
-                                );
-                                let file = file.to_str().unwrap();
+/// Names of the directories next to chapters with rendered images and with block sources,
+/// from `img_dir` and `src_dir`
+pub(crate) fn cache_dirs(options: &Options) -> Result<(String, String)> {
+    let img_dir = options.img_dir.as_deref().unwrap_or("typst-img");
+    let src_dir = options.src_dir.as_deref().unwrap_or("typst-src");
+    for dir in [img_dir, src_dir] {
+        // Pages link images by the name, and the contact sheet reaches them with `../`
+        if dir.is_empty() || dir == "." || dir == ".." || dir.contains(['/', '\\']) {
+            return Err(anyhow!(
+                "img_dir and src_dir must be plain directory names, got \"{}\"",
+                dir
+            ));
+        }
+    }
+    Ok((img_dir.to_owned(), src_dir.to_owned()))
+}
+
+/// `MDBOOK_TYPST_RENDER`, which lets the same config render on CI and build quickly elsewhere
+fn render_override() -> Result<Option<bool>> {
+    match std::env::var("MDBOOK_TYPST_RENDER") {
+        Ok(value) => match value.to_lowercase().as_str() {
+            "true" | "1" | "yes" => Ok(Some(true)),
+            "false" | "0" | "no" => Ok(Some(false)),
+            _ => Err(anyhow!(
+                "MDBOOK_TYPST_RENDER must be true or false, got {}",
+                value
+            )),
+        },
+        Err(_) => Ok(None),
+    }
+}
 
-                                compile_errors.extend(err);
+/// A TOML value passed to Typst as it is written, strings without their quotes
+fn toml_string(value: &toml::Value) -> String {
+    match value {
+        toml::Value::String(s) => s.clone(),
+        v => v.to_string(),
+    }
+}
 
-                                html += format!("<typst-render-insert-image-{file}>").as_str();
-                            }
-                            new_events.push(Event::Html(
-                                format!(r#"<div style="margin-bottom: 0.5em">{}</div>"#, html)
-                                    .into(),
-                            ));
-                            new_events.push(Event::HardBreak);
-                            codeblock_text = None
-                        } else {
-                            new_events.push(Event::End(tag))
-                        }
-                    } else {
-                        new_events.push(Event::End(tag))
-                    }
-                }
-                Event::Code(code) if settings.highlight_inline => {
-                    new_events.push(Event::Html(highlight(code, true).into()))
-                }
-                Event::Text(s) => {
-                    if let Some(ref mut text) = codeblock_text {
-                        text.push_str(&s)
-                    } else {
-                        new_events.push(Event::Text(s))
-                    }
-                }
-                ev => new_events.push(ev),
-            }
+fn class_style(options: &Options) -> Option<ClassStyle> {
+    options.classed.then(|| {
+        match options.scope_class_prefix.as_deref() {
+            // syntect wants a static prefix, and the settings live for the whole run anyway
+            Some(prefix) => ClassStyle::SpacedPrefixed {
+                prefix: Box::leak(prefix.to_owned().into_boxed_str()),
+            },
+            None => ClassStyle::Spaced,
         }
+    })
+}
 
-        let runtime = tokio::runtime::Builder::new_current_thread()
-            .build()
-            .unwrap();
+/// Directories of `font_paths` that exist. Without the option, a `fonts` directory of the
+/// source is used if there is one.
+fn font_paths(options: &Options, root: &Path, config: &Config) -> Vec<PathBuf> {
+    match &options.font_paths {
+        Some(paths) => paths
+            .iter()
+            .map(|path| root.join(path))
+            .filter(|path| {
+                let exists = path.is_dir();
+                if !exists {
+                    warn!("Font path {} doesn't exist, it is skipped", path.display());
+                }
+                exists
+            })
+            .collect(),
+        None => Some(root.join(&config.book.src).join("fonts"))
+            .filter(|path| path.is_dir())
+            .into_iter()
+            .collect(),
+    }
+}
 
-        runtime.block_on(async { join_all(compile_errors).await });
+/// `css_vars` with the `--` of CSS variables
+fn css_vars(options: &Options) -> Vec<(String, String)> {
+    options
+        .css_vars
+        .as_ref()
+        .into_iter()
+        .flatten()
+        .map(|(name, value)| {
+            let name = if name.starts_with("--") {
+                name.clone()
+            } else {
+                format!("--{name}")
+            };
+            (name, toml_string(value))
+        })
+        .collect()
+}
 
-        // Okay, all images are rendered now, so it's time to replace file names with true ones!
+fn allowed_roots(options: &Options, root: &Path) -> Result<Vec<PathBuf>> {
+    options
+        .allowed_roots
+        .iter()
+        .map(|allowed| {
+            root.join(allowed)
+                .canonicalize()
+                .map_err(|err| anyhow!("Invalid root {} in allowed_roots: {}", allowed, err))
+        })
+        .collect()
+}
 
-        let new_events = new_events.into_iter().map(|e| {
-            match e {
-                Event::Html(s) if s.contains("<typst-render-insert-image-") => {
-                    const PATTLENGTH: usize = "<typst-render-insert-image-".len();
+fn pages_layout(options: &Options) -> Result<PagesLayout> {
+    match options.pages_layout.as_deref().unwrap_or("stacked") {
+        "stacked" => Ok(PagesLayout::Stacked),
+        "row" => Ok(PagesLayout::Row),
+        "grid" => Ok(PagesLayout::Grid),
+        other => Err(anyhow!(
+            "Unknown pages_layout {}, expected stacked, row or grid",
+            other
+        )),
+    }
+}
 
-                    let start = s.find("<typst-render-insert-image-").unwrap();
-                    let end = start + PATTLENGTH + s[start+PATTLENGTH..].find('>').expect("Someone who inserts crazy tags forgot to close the bracket");
-                    let file = PathBuf::from_str(&s[start+PATTLENGTH..end]).expect("Problem when decoding path");
+fn remote(options: &Options) -> Result<Option<remote::Remote>> {
+    let Some(url) = options.remote_url.as_deref() else {
+        return Ok(None);
+    };
+    if cfg!(not(feature = "remote")) {
+        return Err(anyhow!(
+            "remote_url needs the preprocessor to be built with the remote feature"
+        ));
+    }
+    let auth = options
+        .remote_auth_env
+        .as_deref()
+        .map(|var| {
+            std::env::var(var).map_err(|err| anyhow!("Can't read remote_auth_env {}: {}", var, err))
+        })
+        .transpose()?;
+    let timeout = options.remote_timeout.unwrap_or(60);
+    Ok(Some(remote::Remote {
+        url: url.to_owned(),
+        auth,
+        timeout: Duration::from_secs(timeout),
+    }))
+}
 
-                    let inner = get_images(file).map(|name| {
-                        format!(
-                        r#"<div style="
-                        text-align: center;
-                        padding: 0.5em;
-                        background: var(--quote-bg);
-                        "><img align="middle" src="typst-img/{name}" alt="Rendered image" style="
-                        background: white;
-                        max-width: 500pt;
-                        width: 100%;
-                    "></div>"#)}).collect::<String>();
-
-                    let new_s = s[..start].to_owned() + inner.as_str() + &s[end+1..];
-
-                    Event::Html(new_s.into())
-                },
-                e => e
-            }
-        });
+/// The highlighting theme, and the one for mdBook's dark themes if it's different
+fn themes(options: &Options, root: &Path) -> Result<(Theme, Option<Theme>)> {
+    let theme_file = options.theme_file.as_deref();
+    let light = options.theme_light.as_deref();
+    let dark = options.theme_dark.as_deref();
+    let chosen = [options.theme.as_deref(), theme_file, light.or(dark)];
+    if chosen.iter().flatten().count() > 1 {
+        return Err(anyhow!(
+            "Only one of theme, theme_file and theme_light/theme_dark can be used"
+        ));
+    }
+    let (theme_name, theme_dark) = match (light, dark) {
+        (Some(light), Some(dark)) => (Some(light), Some(book_theme(dark)?)),
+        // With only one of them, it's just the theme
+        (light, dark) => (options.theme.as_deref().or(light).or(dark), None),
+    };
+    let theme = match theme_file {
+        // Used as it is, as it's usually made for the book's styles
+        Some(file) => ThemeSet::get_theme(root.join(file))
+            .map_err(|err| anyhow!("Can't load theme file {}: {}", file, err))?,
+        None => book_theme(theme_name.unwrap_or(DEFAULT_THEME))?,
+    };
+    Ok((theme, theme_dark))
+}
 
-        let mut buf = String::with_capacity(chapter.content.len());
-        cmark(new_events.into_iter(), &mut buf)
-            .map_err(|err| anyhow!("Markdown serialization failed: {}", err))?;
+fn preamble(options: &Options, root: &Path) -> Result<String> {
+    match (
+        options.preamble.as_deref(),
+        options.preamble_file.as_deref(),
+    ) {
+        (Some(_), Some(_)) => Err(anyhow!("preamble and preamble_file can't be used together")),
+        (Some(preamble), None) => Ok(preamble.to_owned()),
+        (None, Some(file)) => fs::read_to_string(root.join(file))
+            .map_err(|err| anyhow!("Can't read preamble file {}: {}", file, err)),
+        (None, None) => Ok(PREAMBLE.to_owned()),
+    }
+}
 
-        chapter.content = buf;
+fn image_format(options: &Options) -> Result<ImageFormat> {
+    match options.format.as_deref().unwrap_or("svg") {
+        "svg" => Ok(ImageFormat::Svg),
+        "png" => Ok(ImageFormat::Png),
+        other => Err(anyhow!("Unknown format {}, expected svg or png", other)),
     }
-    Ok(())
 }
 
-fn get_lang<'a>(
-    t: &'a Tag,
-    settings: &PreprocessSettings,
-    chapter: Option<&str>,
-) -> Option<&'a str> {
-    let default = if settings.typst_default {
-        Some("typ")
-    } else {
-        None
+/// The ICC profile file of `color_profile`, relative to the book root
+fn color_profile(options: &Options, root: &Path) -> Result<Option<Arc<[u8]>>> {
+    let Some(file) = options.color_profile.as_deref() else {
+        return Ok(None);
     };
-    if let Tag::CodeBlock(ref kind) = *t {
-        match kind {
-            CodeBlockKind::Fenced(kind) => {
-                (!kind.is_empty()).then(|| kind.as_ref()).or_else(|| {
-                    if settings.warn_not_specified {
-                        if let Some(chapter) = chapter {
-                            eprintln!("Codeblock language not specified in {}", chapter)
-                        }
-                    }
-                    default
-                })
-            }
-            CodeBlockKind::Indented => default,
-        }
-    } else {
-        None
+    let profile = fs::read(root.join(file))
+        .map_err(|err| anyhow!("Can't read color profile {}: {}", file, err))?;
+    // The signature of ICC profiles
+    if profile.get(36..40) != Some(b"acsp") {
+        return Err(anyhow!("Color profile {} is not an ICC profile", file));
     }
+    Ok(Some(profile.into()))
 }
 
-fn is_typst_codeblock(s: &str) -> bool {
-    s.contains("typ") || s.contains("typst")
+fn compile_slots(options: &Options) -> Result<Arc<Semaphore>> {
+    let max_concurrency = match options.max_concurrency {
+        Some(0) => return Err(anyhow!("max_concurrency must be at least 1")),
+        Some(n) => n,
+        None => std::thread::available_parallelism().map_or(1, |n| n.get()),
+    };
+    Ok(Arc::new(Semaphore::new(max_concurrency)))
 }
 
-fn highlight(s: CowStr, inline: bool) -> String {
-    let mut s = s.into_string();
-    if s.ends_with('\n') {
-        s.pop();
+/// The default color variables, followed by those of `color_var_map`
+fn color_vars(options: &Options) -> Result<Vec<(Color, String)>> {
+    let mut color_vars = default_color_vars();
+    for (hex, var) in options.color_var_map.as_ref().into_iter().flatten() {
+        let color = parse_color(hex).ok_or_else(|| {
+            anyhow!(
+                "Invalid color {} in color_var_map, expected #rrggbb or #rrggbbaa",
+                hex
+            )
+        })?;
+        let var = var
+            .as_str()
+            .ok_or_else(|| anyhow!("Variable for {} in color_var_map must be a string", hex))?;
+        let var = var.strip_prefix("--").unwrap_or(var);
+        color_vars.push((color, format!("var(--{var})")));
     }
+    Ok(color_vars)
+}
 
-    let syntax = SYNTAX.syntaxes().last().unwrap();
+/// `typst_args`, followed by an `--input` for each of `inputs`
+fn typst_args(options: &Options) -> Vec<String> {
+    let mut typst_args = options.typst_args.clone();
+    for (key, value) in options.inputs.as_ref().into_iter().flatten() {
+        typst_args.push("--input".to_owned());
+        typst_args.push(format!("{key}={}", toml_string(value)));
+    }
+    typst_args
+}
 
-    let mut html = if inline {
-        let mut h = HighlightLines::new(syntax, &THEME);
-        let regs = h.highlight_line(s.as_ref(), &SYNTAX).unwrap(); // everything should be fine
-        let html = styled_line_to_highlighted_html(&regs[..], IncludeBackground::No).unwrap();
-        format!(r#"<code class="hljs">{}</code>"#, html)
-    } else {
-        let mut html = r#"<pre style="margin: 0"><code class="language-typ hljs">"#.into();
+fn side_by_side(options: &Options) -> Result<bool> {
+    match options.layout.as_deref().unwrap_or("stacked") {
+        "stacked" => Ok(false),
+        "side-by-side" => Ok(true),
+        other => Err(anyhow!(
+            "Unknown layout {}, expected stacked or side-by-side",
+            other
+        )),
+    }
+}
 
-        let mut highlighter = HighlightLines::new(syntax, &THEME);
+fn image_max_width(options: &Options) -> Result<String> {
+    let image_max_width = options.image_max_width.as_deref().unwrap_or("500pt");
+    // It's put into the stylesheet as it is
+    if image_max_width.contains([';', '{', '}', '<', '>']) {
+        return Err(anyhow!(
+            "Invalid image_max_width {}, expected a CSS length like 700pt",
+            image_max_width
+        ));
+    }
+    Ok(image_max_width.to_owned())
+}
 
-        for line in LinesWithEndings::from(&s) {
-            let regions = highlighter.highlight_line(line, &SYNTAX).unwrap();
-            append_highlighted_html_for_styled_line(&regions[..], IncludeBackground::No, &mut html)
-                .unwrap();
-        }
+fn image_align(options: &Options) -> Result<String> {
+    match options.image_align.as_deref().unwrap_or("center") {
+        align @ ("left" | "center" | "right") => Ok(align.to_owned()),
+        other => Err(anyhow!(
+            "Unknown image_align {}, expected left, center or right",
+            other
+        )),
+    }
+}
 
-        html.push_str("</code></pre>\n");
+/// Fails on `inline_languages` without a grammar
+fn check_inline_languages(options: &Options) -> Result<()> {
+    match options
+        .inline_languages
+        .iter()
+        .find(|lang| SYNTAX.find_syntax_by_token(lang).is_none())
+    {
+        Some(lang) => Err(anyhow!("No grammar for {} in inline_languages", lang)),
+        None => Ok(()),
+    }
+}
 
-        html
+fn languages(options: &Options) -> Result<Vec<String>> {
+    let languages = match &options.languages {
+        Some(languages) => languages.clone(),
+        None => info_string::TYPST_LANGS.map(str::to_owned).to_vec(),
     };
+    if languages.is_empty() || languages.iter().any(|lang| lang.is_empty()) {
+        return Err(anyhow!(
+            "languages must list at least one language, none empty"
+        ));
+    }
+    Ok(languages)
+}
 
-    html = html.replace("#1bdf3363", "var(--fg)");
-
-    html
+fn library(
+    options: &Options,
+    font_paths: &[PathBuf],
+    package_path: &Option<PathBuf>,
+    package_cache_path: &Option<PathBuf>,
+) -> Result<Option<library::Library>> {
+    match options.backend.as_deref().unwrap_or("cli") {
+        "cli" => Ok(None),
+        "library" if cfg!(not(feature = "library")) => Err(anyhow!(
+            "backend = \"library\" needs the preprocessor to be built with the library feature"
+        )),
+        "library" => Ok(Some(library::Library {
+            font_dirs: font_paths.to_vec(),
+            package_path: package_path.clone(),
+            package_cache_path: package_cache_path.clone(),
+            #[cfg(feature = "library")]
+            fonts: Default::default(),
+        })),
+        other => Err(anyhow!(
+            "Unknown backend {}, expected cli or library",
+            other
+        )),
+    }
 }
 
-fn sha256_hash(input: &str) -> String {
-    let mut res = Sha256::new();
-    res.update(input.as_bytes());
-    let res = res.finalize();
-    format!("{:x}", res)
+/// The page color of dark variants, with `render_dark`
+fn dark_page_fill(options: &Options) -> Result<Option<String>> {
+    if !options.render_dark {
+        return Ok(None);
+    }
+    let fill = options.dark_page_fill.as_deref().unwrap_or("#1f2124");
+    // Also must be a valid Typst color
+    parse_color(fill).ok_or_else(|| {
+        anyhow!(
+            "Invalid dark_page_fill {}, expected #rrggbb or #rrggbbaa",
+            fill
+        )
+    })?;
+    Ok(Some(fill.to_owned()))
 }
 
-fn get_images(src: PathBuf) -> impl Iterator<Item = String> {
-    let mut n = 1;
-    let fbase = src.file_name().unwrap().to_str().unwrap().to_owned();
+impl TypstHighlight {
+    /// Processes the book at `root`, with or without mdbook calling the preprocessor
+    fn preprocess(
+        &self,
+        root: &Path,
+        config: &Config,
+        renderer: &str,
+        mut book: Book,
+    ) -> Result<Book> {
+        let options = Options::from_table(config.get_preprocessor(self.name()))?;
+        let mut settings = PreprocessSettings::new(options, root, config, renderer)?;
 
-    iter::from_fn(move || {
-        let path = src.clone();
-        let path = path.with_file_name(fbase.clone() + format!("-{n}.svg").as_str());
+        // Fails once with a clear message, instead of for every block
+        if settings.render {
+            let compiles = settings.remote.is_none() && settings.library.is_none();
+            if compiles || has_query_blocks(&book, &settings) {
+                std::process::Command::new(&settings.typst_path)
+                    .arg("--version")
+                    .output()
+                    .map_err(|err| match compiles {
+                        true => typst_spawn_error(&settings.typst_path, err),
+                        false => query_spawn_error(&settings.typst_path, err),
+                    })?;
+            }
+        }
 
-        if path.exists() {
-            n += 1;
-            Some(path.file_name().unwrap().to_string_lossy().into_owned())
-        } else {
-            None
+        if settings.highlight_threads > 1 {
+            settings.prehighlighted = prehighlight(&book, &settings)?;
         }
-    })
-    .fuse()
-}
 
-fn render_block(
-    src: String,
-    mut dir: PathBuf,
-    mut build_dir: PathBuf,
-    name: String,
-    preamble: bool,
-) -> (PathBuf, Option<impl Future<Output = ()>>) {
-    let filename = sha256_hash(&src);
-    let mut output = dir.clone();
-    output.push("typst-img");
-    let mut check = output.clone();
-    let mut cut_output = output.clone();
-    cut_output.push(filename.clone());
+        let mut build_dir = root.to_path_buf();
+        build_dir.push(&config.book.src);
 
-    output.push(filename.clone() + "-{n}.svg");
-    check.push(filename.clone() + "-1.svg");
+        let mut figures = vec![];
 
-    let mut command = None;
+        let processed = process_chapters(&book, &settings, &build_dir, &mut figures);
+        let state = &settings.state;
+        if settings.options.collapse_errors {
+            // Also when failing fast, as the output explains the failure
+            state.report_collected_errors();
+        }
+        let used = std::mem::take(&mut *state.used_hashes.lock().unwrap());
+        let failed = std::mem::take(&mut *state.failed_blocks.lock().unwrap());
+        let timed_out = state.timed_out.lock().unwrap().len();
+        // An image can be embedded several times, like in the chapter and in its fragment
+        let not_postprocessed = std::mem::take(&mut *state.not_postprocessed.lock().unwrap())
+            .into_iter()
+            .unique()
+            .count();
+        set_contents(&mut book.sections, &mut processed?.into_iter());
 
-    if !check.exists() {
-        fs::create_dir_all(output.parent().unwrap()).expect("Can't create a dir");
-        dir.push("typst-src");
-        fs::create_dir_all(&dir).expect("Can't create a dir");
-        dir.push(filename.clone() + ".typ");
+        // Without rendering no block is used, so everything would go
+        if settings.options.prune_cache && settings.render {
+            let dirs = iter::once(&build_dir).chain(&settings.allowed_roots);
+            let removed = dirs
+                .map(|dir| prune_unused(dir, &used, &settings))
+                .sum::<usize>();
+            if removed > 0 {
+                info!("Removed {} unused cached files", removed);
+            }
+        }
 
-        let mut file = File::create(&dir).expect("Can't create file");
-        if preamble {
-            writeln!(file, "{}", PREAMBLE).expect("Error writing to file")
-        };
-        write!(file, "{}", src).expect("Error writing to file");
+        if !failed.is_empty() && settings.options.fail_on_error {
+            return Err(anyhow!(
+                "Typst failed to compile {} blocks, in chapters {}",
+                failed.len(),
+                failed
+                    .iter()
+                    .unique()
+                    .map(|name| format!("\"{name}\""))
+                    .join(", ")
+            ));
+        }
 
-        let mut res = Command::new("typst");
-        let mut res = res
-            .arg("c")
-            .arg(&dir)
-            .arg("--root")
-            .arg(dir.parent().unwrap().parent().unwrap())
-            .arg(&output);
+        if not_postprocessed > 0 && settings.options.fail_on_postprocess_error {
+            return Err(anyhow!(
+                "{} images couldn't be post-processed",
+                not_postprocessed
+            ));
+        }
 
-        build_dir.push("fonts");
+        if timed_out > 0 {
+            let message = format!(
+                "Rendering took longer than render_time_limit, {} blocks were not rendered",
+                timed_out
+            );
+            if settings.options.fail_on_time_limit {
+                return Err(anyhow!(message));
+            }
+            warn!("{}", message);
+        }
 
-        if build_dir.exists() {
-            res = res.arg("--font-path").arg(build_dir)
+        if settings.options.assets_list {
+            let assets = figures
+                .iter()
+                .flat_map(|f| f.images.iter().chain(&f.dark_images))
+                .join("\n");
+            fs::write(build_dir.join("typst-assets.txt"), assets + "\n")?;
         }
 
-        let res = res.output();
+        if let Some(gallery) = &settings.gallery {
+            let html = gallery_page(&figures, gallery, &settings);
+            let path = build_dir.join(gallery);
+            // An unchanged page isn't written, so it doesn't wake up watchers
+            if fs::read_to_string(&path).ok().as_ref() != Some(&html) {
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                fs::write(path, html)?;
+            }
+        }
 
-        command = Some(async move {
-            let output = res.await.expect("Failed").stderr;
+        if settings.options.manifest {
+            let manifest = serde_json::to_string_pretty(&figures)?;
+            fs::write(build_dir.join("typst-manifest.json"), manifest)?;
+        }
 
-            if !output.is_empty() {
-                let stderr = std::io::stderr();
-                let mut handle = stderr.lock();
-                writeln!(handle, "Error at chapter \"{}\"\n", name).expect("Can't write to stderr");
-                handle.write_all(&output).expect("Can't write to stderr");
-            }
-        });
+        if settings.options.checksums {
+            // In the format of `sha256sum`, so the images can be checked with `sha256sum -c`
+            let checksums = figures
+                .iter()
+                .flat_map(|f| f.images.iter().chain(&f.dark_images))
+                .unique()
+                .map(|image| {
+                    let content = fs::read(build_dir.join(image))?;
+                    Ok(format!("{:x}  {}\n", Sha256::digest(content), image))
+                })
+                .collect::<Result<String>>()?;
+            fs::write(build_dir.join("typst-checksums.txt"), checksums)?;
+        }
+
+        Ok(book)
+    }
+}
+
+impl Preprocessor for TypstHighlight {
+    fn name(&self) -> &str {
+        "typst-highlight"
     }
 
-    (cut_output, command)
+    fn run(&self, ctx: &PreprocessorContext, book: Book) -> Result<Book, Error> {
+        self.preprocess(&ctx.root, &ctx.config, &ctx.renderer, book)
+    }
+
+    fn supports_renderer(&self, renderer: &str) -> bool {
+        matches!(renderer, "html" | "epub")
+    }
+}
+
+/// Processes every chapter in reading order, returning their new contents in the same order.
+/// The blocks of all chapters are rendered at once, instead of chapter by chapter,
+/// so big books aren't held up by a few slow blocks in every chapter.
+fn process_chapters(
+    book: &Book,
+    settings: &PreprocessSettings,
+    build_dir: &Path,
+    figures: &mut Vec<Figure>,
+) -> Result<Vec<String>> {
+    let mut figure_count = 0;
+    let mut compiles = vec![];
+    let chapters = book
+        .iter()
+        .filter_map(|item| match item {
+            BookItem::Chapter(chapter) => Some(chapter),
+            _ => None,
+        })
+        .map(|chapter| {
+            process_chapter(
+                chapter,
+                settings,
+                build_dir,
+                &mut figure_count,
+                &mut compiles,
+            )
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    // `max_concurrency` limits the Typst processes running at once
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_time()
+        .build()
+        .unwrap();
+
+    if settings.options.fail_fast {
+        // Dropping the other compilations kills their processes
+        runtime.block_on(try_join_all(compiles))?;
+    } else {
+        // Errors are already reported
+        runtime.block_on(join_all(compiles));
+    }
+
+    // Okay, all images are rendered now, so it's time to replace file names with true ones!
+    chapters
+        .into_iter()
+        .map(|chapter| finish_chapter(chapter, settings, build_dir, figures, &runtime))
+        .collect()
+}
+
+/// Sets the contents returned by `process_chapters`, in the same order
+fn set_contents(items: &mut [BookItem], contents: &mut impl Iterator<Item = String>) {
+    for item in items {
+        if let BookItem::Chapter(chapter) = item {
+            chapter.content = contents.next().expect("Every chapter is processed");
+            set_contents(&mut chapter.sub_items, contents);
+        }
+    }
+}
+
+/// A chapter whose blocks are being rendered
+struct PendingChapter<'a> {
+    chapter: &'a Chapter,
+    events: Vec<Event<'a>>,
+    /// How the rendered blocks are shown, by their output path
+    blocks: HashMap<String, BlockDisplay>,
+    chapter_path: PathBuf,
+    chapter_url: String,
+}
+
+/// Highlights the chapter and starts rendering its blocks, which are added to `compiles`
+fn process_chapter<'a>(
+    chapter: &'a Chapter,
+    settings: &PreprocessSettings,
+    build_dir: &Path,
+    figure_count: &mut usize,
+    compiles: &mut Vec<LocalBoxFuture<'static, Result<()>>>,
+) -> Result<PendingChapter<'a>> {
+    if settings.figure_number_reset {
+        *figure_count = 0;
+    }
+    // Unnumbered chapters, like prefix chapters, are `0`
+    let chapter_number = chapter
+        .number
+        .as_ref()
+        .map_or("0".to_owned(), |number| number.iter().join("."));
+
+    // Where the current event starts in the chapter, to point errors at their lines
+    let offset = Cell::new(0);
+    let events = new_cmark_parser(&chapter.content, false)
+        .into_offset_iter()
+        .map(|(event, range)| {
+            offset.set(range.start);
+            event
+        });
+    let line_starts = iter::once(0)
+        .chain(chapter.content.match_indices('\n').map(|(i, _)| i + 1))
+        .collect::<Vec<_>>();
+    let line_at = |offset: usize| line_starts.partition_point(|&start| start <= offset);
+    let mut new_events = Vec::new();
+    let mut codeblock_text = None;
+    // Line of the chapter where the code of the current block starts
+    let mut codeblock_line = 0;
+
+    let mut chapter_path = build_dir.to_path_buf();
+    // Path of the chapter directory relative to the book root, as used in URLs
+    let mut chapter_url = String::new();
+    if let Some(p) = chapter.path.as_ref().and_then(|p| p.parent()) {
+        chapter_path.push(p);
+        p.components().for_each(|c| {
+            chapter_url.push_str(&c.as_os_str().to_string_lossy());
+            chapter_url.push('/');
+        });
+    };
+
+    // `chapter.preamble.typ` next to `chapter.md` replaces the default preamble
+    let chapter_preamble = chapter
+        .path
+        .as_ref()
+        .map(|p| build_dir.join(p.with_extension("preamble.typ")))
+        .filter(|p| p.exists())
+        .map(fs::read_to_string)
+        .transpose()?;
+    let preamble = chapter_preamble.as_deref().unwrap_or(&settings.preamble);
+
+    // How the rendered blocks are shown, by their output path
+    let mut blocks = HashMap::new();
+
+    let mut events = events.peekable();
+    // Whether the next text starts with the closing delimiter of inline math
+    let mut math_closing = false;
+
+    while let Some(event) = events.next() {
+        // Before anything peeks at the next event
+        let line = line_at(offset.get());
+        match event {
+            Event::Start(tag) => {
+                let spec = get_block_spec(&tag, settings, None);
+
+                if let Some(spec) = spec {
+                    if spec.is_typst(&settings.languages) {
+                        // The code of a fenced block starts after the fence
+                        codeblock_line = match tag {
+                            Tag::CodeBlock(CodeBlockKind::Fenced(_)) => line + 1,
+                            _ => line,
+                        };
+                        codeblock_text = Some(String::new())
+                    } else if matches!(tag, Tag::CodeBlock(CodeBlockKind::Fenced(_)))
+                        && info_string::is_typst_lang(&spec.lang, &settings.languages)
+                    {
+                        let source = &chapter.content[offset.get()..];
+                        new_events.push(verbatim_block(source, &mut events));
+                    } else {
+                        new_events.push(Event::Start(tag))
+                    }
+                } else {
+                    new_events.push(Event::Start(tag))
+                }
+            }
+            Event::End(tag) => {
+                let spec = get_block_spec(&tag, settings, Some(&chapter.name));
+
+                if let Some(spec) = spec {
+                    if spec.is_typst(&settings.languages) {
+                        let text = codeblock_text.ok_or(anyhow!(
+                            "Typst codeblock wasn't created: chapter {}.
+                                Data collected: {:?}",
+                            chapter.name,
+                            new_events
+                        ))?;
+                        let (shown, text, comment_caption, skipped_lines) =
+                            block_source(text, settings);
+                        // Errors point at the chapter's lines of the compiled code
+                        let code_line = codeblock_line + skipped_lines;
+                        let caption = spec.attr("caption").map(str::to_owned).or(comment_caption);
+                        let caption = caption.map(|caption| {
+                            *figure_count += 1;
+                            let number = settings
+                                .figure_number
+                                .replace("{chapter}", &chapter_number)
+                                .replace("{n}", &figure_count.to_string());
+                            settings
+                                .caption_template
+                                .replace("{number}", &number)
+                                .replace("{caption}", &caption)
+                        });
+
+                        let mut html = highlight_cached(&shown, typst_syntax(), false, settings);
+                        if settings.options.line_numbers {
+                            html = with_line_numbers(&html, &shown, &settings.class_prefix);
+                        }
+
+                        if let Some(themes) = spec.attr("themes") {
+                            html = theme_gallery(
+                                &shown,
+                                &info_string::list(themes),
+                                &settings.class_prefix,
+                            )
+                            .map_err(|err| anyhow!("{} in chapter {}", err, chapter.name))?;
+                        }
+
+                        if spec.flag("noindex") {
+                            html = hide_from_search(&html);
+                        }
+
+                        let root = spec
+                            .attr("root")
+                            .map(|root| settings.allowed_root(root))
+                            .transpose()
+                            .map_err(|err| anyhow!("{} in chapter {}", err, chapter.name))?
+                            .or_else(|| settings.typst_root.clone());
+                        let block_preamble = spec
+                            .attr("preamble")
+                            .map(|file| {
+                                fs::read_to_string(chapter_path.join(file)).map_err(|err| {
+                                    anyhow!(
+                                        "Can't read preamble {} in chapter {}: {}",
+                                        file,
+                                        chapter.name,
+                                        err
+                                    )
+                                })
+                            })
+                            .transpose()?;
+                        if settings.remote.is_some() {
+                            // Only the source is sent, and only the first page comes back
+                            for attr in ["seed", "pages"] {
+                                if spec.attr(attr).is_some() {
+                                    return Err(anyhow!(
+                                        "{}= doesn't work with remote_url, in chapter {}",
+                                        attr,
+                                        chapter.name
+                                    ));
+                                }
+                            }
+                        }
+                        let options = BlockOptions {
+                            preamble: (!spec.modifier("nopreamble")).then_some(preamble),
+                            block_preamble,
+                            root,
+                            seed: spec
+                                .attr("seed")
+                                .map(str::to_owned)
+                                .or_else(|| settings.seed.clone()),
+                            transparent: match spec.attr("bg") {
+                                None => settings.options.transparent_background,
+                                Some("white") => false,
+                                Some("transparent") => true,
+                                Some(bg) => {
+                                    return Err(anyhow!(
+                                        "Unknown bg={} in chapter {}, expected white or transparent",
+                                        bg,
+                                        chapter.name
+                                    ))
+                                }
+                            },
+                            pages: spec.attr("pages").map(str::to_owned),
+                            width: spec
+                                .attr("width")
+                                .map(|width| {
+                                    is_length(width).then(|| width.to_owned()).ok_or_else(|| {
+                                        anyhow!(
+                                            "Invalid width={} in chapter {}, expected a length like 600pt or auto",
+                                            width,
+                                            chapter.name
+                                        )
+                                    })
+                                })
+                                .transpose()?,
+                            dark: false,
+                        };
+
+                        if let Some(selector) = spec.attr("query").filter(|_| settings.render) {
+                            let (file, err) = query_block(
+                                text,
+                                selector,
+                                &options,
+                                chapter_path.clone(),
+                                build_dir.to_path_buf(),
+                                BlockOrigin {
+                                    chapter: chapter.name.clone(),
+                                    line: Some(code_line),
+                                },
+                                settings,
+                            )?;
+                            let file = file.to_str().unwrap();
+
+                            compiles.extend(err);
+
+                            html += format!("<typst-render-insert-query-{file}>").as_str();
+                        } else if settings.render && !spec.modifier("norender") {
+                            let dark = settings
+                                .dark_page_fill
+                                .is_some()
+                                .then(|| {
+                                    render_block(
+                                        text.clone(),
+                                        &BlockOptions {
+                                            dark: true,
+                                            ..options.clone()
+                                        },
+                                        chapter_path.clone(),
+                                        build_dir.to_path_buf(),
+                                        BlockOrigin {
+                                            chapter: chapter.name.clone(),
+                                            line: Some(code_line),
+                                        },
+                                        settings,
+                                    )
+                                })
+                                .transpose()?;
+                            let rendered = render_block(
+                                text,
+                                &options,
+                                chapter_path.clone(),
+                                build_dir.to_path_buf(),
+                                BlockOrigin {
+                                    chapter: chapter.name.clone(),
+                                    line: Some(code_line),
+                                },
+                                settings,
+                            )?;
+                            let file = rendered.path.to_str().unwrap();
+                            blocks.insert(
+                                file.to_owned(),
+                                BlockDisplay {
+                                    deps: rendered.deps,
+                                    background: spec.flag("background"),
+                                    transparent: options.transparent,
+                                    caption: caption.clone(),
+                                    alt: spec.attr("alt").map(str::to_owned),
+                                    dark: dark.as_ref().map(|dark| dark.path.clone()),
+                                },
+                            );
+
+                            // The dark variant only compiles if the block does, as its errors
+                            // would just repeat those of the block
+                            compiles.extend(
+                                match (rendered.compile, dark.and_then(|d| d.compile)) {
+                                    (Some(light), Some(dark)) => {
+                                        Some(light.and_then(|_| dark).boxed_local())
+                                    }
+                                    (light, dark) => light.or(dark),
+                                },
+                            );
+
+                            let image = format!("<typst-render-insert-image-{file}>");
+                            if settings.side_by_side || spec.modifier("sidebyside") {
+                                html = format!(
+                                    r#"<div class="typst-side-by-side"><div class="typst-side-source">{html}</div><div class="typst-side-render">{image}</div></div>"#
+                                );
+                            } else {
+                                html += &image;
+                            }
+                        }
+                        let html = match &caption {
+                            Some(caption) => format!(
+                                r#"<figure class="typst-figure">{html}<figcaption class="typst-caption">{}</figcaption></figure>"#,
+                                escape_html(caption)
+                            ),
+                            None => {
+                                format!(r#"<div style="margin-bottom: 0.5em">{}</div>"#, html)
+                            }
+                        };
+                        new_events.push(Event::Html(html.into()));
+                        new_events.push(Event::HardBreak);
+                        codeblock_text = None
+                    } else {
+                        new_events.push(Event::End(tag))
+                    }
+                } else {
+                    new_events.push(Event::End(tag))
+                }
+            }
+            Event::Code(code)
+                if settings.highlight_inline
+                    && settings
+                        .max_inline_length
+                        .is_some_and(|max| code.chars().count() > max) =>
+            {
+                warn!(
+                    "Inline code in {} is too long to be highlighted: `{}…`",
+                    chapter.name,
+                    code.chars().take(20).collect::<String>()
+                );
+                new_events.push(Event::Code(code))
+            }
+            Event::Code(code)
+                if settings.highlight_inline
+                    && (is_inline_math(&new_events, events.peek(), &settings.math_delimiter)
+                        || settings.math_prefix && code.starts_with(MATH_PREFIX)) =>
+            {
+                if is_inline_math(&new_events, events.peek(), &settings.math_delimiter) {
+                    if let Some(Event::Text(before)) = new_events.pop() {
+                        let before = &before[..before.len() - settings.math_delimiter.len()];
+                        new_events.push(Event::Text(before.to_owned().into()));
+                    }
+                    math_closing = true;
+                }
+
+                let code = code.strip_prefix(MATH_PREFIX).unwrap_or(&code);
+                let math = format!("${code}$");
+                let html = highlight_inline(&math, typst_syntax(), settings);
+
+                if let Some(class) = &settings.client_math_class {
+                    // The highlighted code is shown until the script replaces it
+                    new_events.push(Event::Html(
+                        format!(
+                            r#"<span class="{class}" data-typst="{}">{html}</span>"#,
+                            escape_html(&math)
+                        )
+                        .into(),
+                    ))
+                } else if settings.render_inline {
+                    let options = BlockOptions {
+                        preamble: Some(INLINE_MATH_PREAMBLE),
+                        block_preamble: None,
+                        root: None,
+                        seed: settings.seed.clone(),
+                        transparent: false,
+                        pages: None,
+                        width: None,
+                        dark: false,
+                    };
+                    let rendered = render_block(
+                        math,
+                        &options,
+                        chapter_path.clone(),
+                        build_dir.to_path_buf(),
+                        BlockOrigin {
+                            chapter: chapter.name.clone(),
+                            line: Some(line),
+                        },
+                        settings,
+                    )?;
+                    let file = rendered.path.to_str().unwrap();
+                    compiles.extend(rendered.compile);
+
+                    // The highlighted code stays as a fallback if the math doesn't compile
+                    new_events.push(Event::Html(
+                        format!("<typst-render-insert-inline-{file}>{html}").into(),
+                    ))
+                } else {
+                    new_events.push(Event::Html(html.into()))
+                }
+            }
+            Event::Code(code) if settings.inline_preview && code.starts_with(PREVIEW_PREFIX) => {
+                let code = &code[PREVIEW_PREFIX.len()..];
+                let html = highlight_inline(code, typst_syntax(), settings);
+                let options = BlockOptions {
+                    preamble: Some(INLINE_PREAMBLE),
+                    block_preamble: None,
+                    root: None,
+                    seed: settings.seed.clone(),
+                    transparent: false,
+                    pages: None,
+                    width: None,
+                    dark: false,
+                };
+                let rendered = render_block(
+                    code.to_owned(),
+                    &options,
+                    chapter_path.clone(),
+                    build_dir.to_path_buf(),
+                    BlockOrigin {
+                        chapter: chapter.name.clone(),
+                        line: Some(line),
+                    },
+                    settings,
+                )?;
+                let file = rendered.path.to_str().unwrap();
+                compiles.extend(rendered.compile);
+
+                new_events.push(Event::Html(
+                    format!("<typst-render-insert-preview-{file}>{html}").into(),
+                ))
+            }
+            Event::Code(code) if settings.highlight_inline => {
+                match split_inline_lang(&code, settings) {
+                    Some((syntax, code)) => new_events
+                        .push(Event::Html(highlight_inline(code, syntax, settings).into())),
+                    None if !settings.options.inline_require_lang => new_events.push(Event::Html(
+                        highlight_inline(&code, typst_syntax(), settings).into(),
+                    )),
+                    None => new_events.push(Event::Code(code)),
+                }
+            }
+            Event::Text(s) if math_closing => {
+                math_closing = false;
+                let after = &s[settings.math_delimiter.len()..];
+                new_events.push(Event::Text(after.to_owned().into()))
+            }
+            Event::Text(s) => {
+                if let Some(ref mut text) = codeblock_text {
+                    push_block_text(text, &s, settings)
+                } else {
+                    new_events.push(Event::Text(s))
+                }
+            }
+            ev => new_events.push(ev),
+        }
+    }
+
+    Ok(PendingChapter {
+        chapter,
+        events: new_events,
+        blocks,
+        chapter_path,
+        chapter_url,
+    })
+}
+
+/// Replaces the rendered blocks of the chapter with their images, returning the new content
+fn finish_chapter(
+    pending: PendingChapter,
+    settings: &PreprocessSettings,
+    build_dir: &Path,
+    figures: &mut Vec<Figure>,
+    runtime: &tokio::runtime::Runtime,
+) -> Result<String> {
+    let PendingChapter {
+        chapter,
+        events: new_events,
+        blocks,
+        chapter_path,
+        chapter_url,
+    } = pending;
+
+    let mut chapter_images = vec![];
+    let chapter_figures = figures.len();
+    let mut seen_hashes = HashSet::new();
+    // Images embedded into the chapter so far, to keep their ids apart
+    let mut embeds = 0;
+
+    let new_events = new_events.into_iter().map(|e| -> Result<Event> {
+        Ok(match e {
+            Event::Html(s) if s.contains("<typst-render-insert-image-") => {
+                const PATTLENGTH: usize = "<typst-render-insert-image-".len();
+
+                let start = s.find("<typst-render-insert-image-").unwrap();
+                let end = start + PATTLENGTH + s[start+PATTLENGTH..].find('>').expect("Someone who inserts crazy tags forgot to close the bracket");
+                let file = PathBuf::from_str(&s[start+PATTLENGTH..end]).expect("Problem when decoding path");
+                let hash = file.file_name().unwrap().to_string_lossy().into_owned();
+                let display = blocks.get(&s[start+PATTLENGTH..end]);
+                let deps = display.map(|d| d.deps.as_slice()).unwrap_or_default();
+                let wrapper = if display.is_some_and(|d| d.transparent) {
+                    "typst-render typst-transparent"
+                } else {
+                    "typst-render"
+                };
+
+                let mut figure = Figure::new(chapter, &hash, deps, build_dir);
+                figure.caption = display.and_then(|d| d.caption.clone());
+                figure.alt = display.and_then(|d| d.alt.clone());
+                // Users describe their images with `alt=`, this is for the rest
+                let alt = escape_html(figure.alt.as_deref().unwrap_or("Rendered image"));
+
+                let names = get_images(file).collect::<Vec<_>>();
+                for name in &names {
+                    figure.images.push(format!("{chapter_url}{}/{name}", settings.img_dir));
+                    chapter_images.push(name.clone());
+                }
+                let dark_names = display
+                    .and_then(|d| d.dark.clone())
+                    .map(|dark| get_images(dark).collect::<Vec<_>>())
+                    .unwrap_or_default();
+                for name in &dark_names {
+                    figure.dark_images.push(format!("{chapter_url}{}/{name}", settings.img_dir));
+                }
+                let mut image = |name: &String| {
+                    let path = chapter_path.join(&settings.img_dir).join(name);
+                    // Saves a request for small images, big ones are better cached as files
+                    let embed = name.ends_with(".svg")
+                        && (settings.options.inline_svg
+                            || settings.embed_max_bytes.is_some_and(|max| {
+                                fs::metadata(&path).is_ok_and(|m| m.len() <= max)
+                            }));
+                    let svg = embed
+                        .then(|| settings.state.postprocessed(inline_svg(&path), &path))
+                        .flatten()
+                        .map(|svg| {
+                            embeds += 1;
+                            scoped_ids(&svg, &format!("typst{embeds}-"))
+                        });
+                    if svg.is_some() || settings.offline {
+                        figure.embedded.push(format!("{chapter_url}{}/{name}", settings.img_dir));
+                    }
+                    svg.unwrap_or_else(|| {
+                        let src = image_src(name, &chapter_path, settings);
+                        let attrs = img_attrs(&path, settings);
+                        format!(r#"<img src="{src}"{attrs} alt="{alt}">"#)
+                    })
+                };
+                let imgs = names.iter().enumerate().map(|(i, name)| {
+                    let light = image(name);
+                    // Both are in the page, the stylesheet shows the one for the mdBook theme
+                    match dark_names.get(i) {
+                        Some(dark) => format!(
+                            r#"<span class="typst-render-light">{light}</span><span class="typst-render-dark">{}</span>"#,
+                            image(dark)
+                        ),
+                        None => light,
+                    }
+                }).collect::<Vec<_>>();
+
+                let mut inner = if display.is_some_and(|d| d.background) {
+                    names.iter().map(|name| format!(
+                        r#"<div class="typst-render typst-background" style="background-image: url('{}')" role="img" aria-label="{alt}"></div>"#,
+                        image_src(name, &chapter_path, settings)
+                    )).collect()
+                } else {
+                    match settings.pages_layout {
+                        PagesLayout::Row if imgs.len() > 1 => {
+                            format!(r#"<div class="{wrapper} typst-pages-row">{}</div>"#, imgs.concat())
+                        }
+                        PagesLayout::Grid if imgs.len() > 1 => {
+                            format!(r#"<div class="{wrapper} typst-pages-grid">{}</div>"#, imgs.concat())
+                        }
+                        _ => imgs
+                            .iter()
+                            .map(|img| format!(r#"<div class="{wrapper}">{img}</div>"#))
+                            .collect(),
+                    }
+                };
+
+                if names.is_empty() && settings.state.timed_out.lock().unwrap().contains(&hash) {
+                    inner = r#"<div class="typst-render typst-placeholder">Not rendered, the render time limit was exceeded</div>"#.to_owned();
+                }
+
+                let deduped = settings.options.dedupe_in_chapter && !figure.images.is_empty();
+                let duplicate = deduped && !seen_hashes.insert(hash.clone());
+                if duplicate {
+                    // The caption, if any, stays, as it belongs to this place
+                    inner = format!(
+                        r##"<div class="typst-render typst-duplicate"><a href="#typst-{hash}">Same as the figure above</a></div>"##
+                    );
+                } else if deduped {
+                    inner = format!(r#"<span id="typst-{hash}"></span>{inner}"#);
+                }
+
+                // Failed compilations have no images and were already reported
+                if !figure.images.is_empty() && !duplicate {
+                    if let Some(fragments_dir) = &settings.fragments_dir {
+                        let images = figure.images.iter().map(|url| {
+                            chapter_path.join(&settings.img_dir).join(url.rsplit('/').next().unwrap())
+                        });
+                        let fragment = fragment(&s[..start], images, &s[end + 1..], &alt, settings);
+                        let path = fragments_dir.join(hash.clone() + ".html");
+                        fs::create_dir_all(fragments_dir)
+                            .and_then(|_| fs::write(&path, fragment))
+                            .map_err(|err| anyhow!("Can't write fragment {}: {}", path.display(), err))?;
+                    }
+
+                    figures.push(figure);
+                }
+
+                // Sources outside of the chapter (see `root=`) aren't published next to it
+                let source = format!("{}/{hash}.typ", settings.src_dir);
+                if settings.options.source_link && chapter_path.join(&source).exists() {
+                    inner += &format!(
+                        r#"<div class="typst-source-link"><a href="{source}" download>Download .typ</a></div>"#
+                    );
+                }
+
+                if settings.debug_hash {
+                    inner += &format!(
+                        r#"<div style="text-align: right; font-size: 0.7em; opacity: 0.6" title="{hash}">{}</div>"#,
+                        &hash[..12]
+                    );
+                }
+
+                let new_s = s[..start].to_owned() + inner.as_str() + &s[end+1..];
+
+                Event::Html(new_s.into())
+            },
+            Event::Html(s) if s.starts_with("<typst-render-insert-inline-") => {
+                const PATTLENGTH: usize = "<typst-render-insert-inline-".len();
+
+                let end = s.find('>').unwrap();
+                let file = PathBuf::from_str(&s[PATTLENGTH..end]).expect("Problem when decoding path");
+
+                match get_images(file).next() {
+                    Some(name) => {
+                        let path = chapter_path.join(&settings.img_dir).join(&name);
+                        // The baseline is in the middle of the image
+                        let attrs = match image_size(&path, settings) {
+                            Some((width, height)) => format!(
+                                r#" width="{width}" height="{height}" style="vertical-align: -{}px""#,
+                                height as f64 / 2.0
+                            ),
+                            None => String::new(),
+                        };
+                        Event::Html(format!(
+                            r#"<img class="typst-render-inline" src="{}"{attrs} alt="Rendered math">"#,
+                            image_src(&name, &chapter_path, settings)
+                        ).into())
+                    },
+                    // Failed compilations were already reported
+                    None => Event::Html(s[end + 1..].to_owned().into()),
+                }
+            },
+            Event::Html(s) if s.starts_with("<typst-render-insert-preview-") => {
+                const PATTLENGTH: usize = "<typst-render-insert-preview-".len();
+
+                let end = s.find('>').unwrap();
+                let file = PathBuf::from_str(&s[PATTLENGTH..end]).expect("Problem when decoding path");
+                let html = &s[end + 1..];
+
+                match get_images(file).next() {
+                    Some(name) => Event::Html(format!(
+                        r#"<span class="typst-preview" tabindex="0">{html}<span class="typst-preview-tooltip"><img src="{}" alt="Rendered code"></span></span>"#,
+                        image_src(&name, &chapter_path, settings)
+                    ).into()),
+                    // Failed compilations were already reported
+                    None => Event::Html(html.to_owned().into()),
+                }
+            },
+            Event::Html(s) if s.contains("<typst-render-insert-query-") => {
+                const PATTLENGTH: usize = "<typst-render-insert-query-".len();
+
+                let start = s.find("<typst-render-insert-query-").unwrap();
+                let end = start + PATTLENGTH + s[start+PATTLENGTH..].find('>').expect("Someone who inserts crazy tags forgot to close the bracket");
+                let file = PathBuf::from_str(&s[start+PATTLENGTH..end]).expect("Problem when decoding path");
+
+                // Failed queries were already reported, so they just produce no output
+                let inner = fs::read_to_string(file)
+                    .ok()
+                    .and_then(|json| serde_json::from_str::<serde_json::Value>(&json).ok())
+                    .map(|json| {
+                        let json = serde_json::to_string_pretty(&json).unwrap();
+                        let syntax = SYNTAX.find_syntax_by_token("json").unwrap();
+                        highlight_themed(&json, syntax, false, settings)
+                    })
+                    .unwrap_or_default();
+
+                let new_s = s[..start].to_owned() + inner.as_str() + &s[end+1..];
+
+                Event::Html(new_s.into())
+            },
+            e => e
+        })
+    });
+
+    let new_events = new_events.collect::<Result<Vec<_>>>()?;
+
+    if let Some(figure) = figures.get_mut(chapter_figures) {
+        figure.og_image = settings.options.og_image;
+    }
+    let has_renders = new_events
+        .iter()
+        .any(|e| {
+        matches!(e, Event::Html(s) if s.contains(r#"class="typst-render"#) || s.contains(r#"class="typst-preview""#))
+    });
+
+    let has_highlights = (settings.class_style.is_some()
+        || settings.theme_dark.is_some()
+        || settings.options.line_numbers)
+        && new_events.iter().any(|e| matches!(e, Event::Html(_)));
+
+    let style = (has_renders || has_highlights)
+        .then(|| Event::Html(stylesheet(settings, has_renders).into()));
+
+    let contact_sheet = (settings.options.contact_sheet && !chapter_images.is_empty()).then(|| -> Result<_> {
+        let RenderedBlock {
+            path: file,
+            compile,
+            ..
+        } = render_contact_sheet(
+            &chapter_images,
+            chapter_path.clone(),
+            build_dir.to_path_buf(),
+            chapter.name.clone(),
+            settings,
+        )?;
+        if let Some(compile) = compile {
+            let res = runtime.block_on(compile);
+            if settings.options.fail_fast {
+                res?;
+            }
+        }
+
+        let hash = file.file_name().unwrap().to_string_lossy().into_owned();
+        let mut figure = Figure::new(chapter, &hash, &[], build_dir);
+
+        let inner = get_images(file).map(|name| {
+            figure.images.push(format!("{chapter_url}{}/{name}", settings.img_dir));
+            let path = chapter_path.join(&settings.img_dir).join(&name);
+            let svg = (settings.options.inline_svg && name.ends_with(".svg"))
+                .then(|| settings.state.postprocessed(inline_svg(&path), &path))
+                .flatten();
+            if let Some(svg) = svg {
+                figure.embedded.push(format!("{chapter_url}{}/{name}", settings.img_dir));
+                return format!(
+                    r#"<div class="typst-render">{}</div>"#,
+                    scoped_ids(&svg, "typst-sheet-")
+                );
+            }
+            format!(
+                r#"<div class="typst-render"><img src="{}"{} alt="All rendered images of the chapter"></div>"#,
+                image_src(&name, &chapter_path, settings),
+                img_attrs(&path, settings)
+            )
+        }).collect::<String>();
+        figures.push(figure);
+        // Html blocks only end at a blank line
+        Ok(Event::Html((inner + "\n\n").into()))
+    }).transpose()?;
+
+    let mut buf = String::with_capacity(chapter.content.len());
+    cmark(
+        style.into_iter().chain(contact_sheet).chain(new_events),
+        &mut buf,
+    )
+    .map_err(|err| anyhow!("Markdown serialization failed: {}", err))?;
+
+    Ok(buf)
+}
+
+/// A standalone page with every figure of the book, linking to their chapters
+fn gallery_page(figures: &[Figure], path: &Path, settings: &PreprocessSettings) -> String {
+    // Figure URLs are relative to the book root
+    let prefix = "../".repeat(path.components().count().saturating_sub(1));
+    let items = figures
+        .iter()
+        .filter(|figure| !figure.images.is_empty())
+        .map(|figure| {
+            let chapter = escape_html(&figure.chapter);
+            let title = match &figure.page {
+                Some(page) => format!(r#"<a href="{prefix}{page}">{chapter}</a>"#),
+                None => chapter,
+            };
+            let alt = escape_html(figure.alt.as_deref().unwrap_or("Rendered image"));
+            let images = figure
+                .images
+                .iter()
+                .map(|image| {
+                    format!(
+                        r#"<div class="typst-render"><img src="{prefix}{image}" alt="{alt}"></div>"#
+                    )
+                })
+                .collect::<String>();
+            let caption = figure
+                .caption
+                .as_ref()
+                .map(|caption| {
+                    format!(
+                        r#"<div class="typst-caption">{}</div>"#,
+                        escape_html(caption)
+                    )
+                })
+                .unwrap_or_default();
+            format!("<section>\n<h2>{title}</h2>\n{images}{caption}\n</section>\n")
+        })
+        .collect::<String>();
+
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>Figures</title>\n{}</head>\n<body>\n<h1>Figures</h1>\n{items}</body>\n</html>\n",
+        stylesheet(settings, true)
+    )
+}
+
+/// Self-contained HTML of a rendered block, for use outside of the book.
+/// `before` and `after` are the highlighted code around the images.
+fn fragment(
+    before: &str,
+    images: impl Iterator<Item = PathBuf>,
+    after: &str,
+    alt: &str,
+    settings: &PreprocessSettings,
+) -> String {
+    let images = images
+        .map(|image| {
+            if image.extension().is_some_and(|ext| ext == "png") {
+                return format!(
+                    r#"<div class="typst-render"><img src="{}" alt="{alt}"></div>"#,
+                    data_uri(&image)
+                );
+            }
+            // A fragment has no files next to it, so even the unprocessed image is embedded
+            let svg = settings
+                .state
+                .postprocessed(inline_svg(&image), &image)
+                .unwrap_or_else(|| fs::read_to_string(&image).unwrap_or_default());
+            format!(r#"<div class="typst-render">{svg}</div>"#)
+        })
+        .collect::<String>();
+
+    stylesheet(settings, true) + before + &images + after
+}
+
+/// An SVG file as markup that can be put into HTML
+fn inline_svg(path: &Path) -> Result<String> {
+    let svg = fs::read_to_string(path)?;
+    // The XML declaration is not allowed inside HTML
+    let start = svg.find("<svg").ok_or(anyhow!("no <svg> tag"))?;
+    Ok(svg[start..].to_owned())
+}
+
+/// Prefixes the ids of an inline SVG and the references to them.
+/// A page can have the same image twice, or images with the same glyph ids,
+/// and ids must be unique in the whole page.
+fn scoped_ids(svg: &str, prefix: &str) -> String {
+    svg.replace(r#" id=""#, &format!(r#" id="{prefix}"#))
+        .replace(r##"href="#"##, &format!(r##"href="#{prefix}"##))
+        .replace("url(#", &format!("url(#{prefix}"))
+}
+
+/// Size and loading attributes of an `<img>`. With the size, the browser keeps the space
+/// for the image before it is loaded, and the page doesn't jump.
+fn img_attrs(path: &Path, settings: &PreprocessSettings) -> String {
+    let mut attrs = match image_size(path, settings) {
+        Some((width, height)) => format!(r#" width="{width}" height="{height}""#),
+        None => String::new(),
+    };
+    if settings.options.lazy_images {
+        attrs += r#" loading="lazy""#;
+    }
+    attrs
+}
+
+/// Size of a rendered image in CSS pixels
+fn image_size(path: &Path, settings: &PreprocessSettings) -> Option<(u64, u64)> {
+    let (width, height) = if path.extension().is_some_and(|ext| ext == "png") {
+        // The size is in the IHDR chunk, which always comes first
+        let mut header = [0; 24];
+        std::io::Read::read_exact(&mut File::open(path).ok()?, &mut header).ok()?;
+        let pixels = |i: usize| u32::from_be_bytes(header[i..i + 4].try_into().unwrap()) as f64;
+        // Typst renders 144 pixels per inch by default, CSS has 96
+        let scale = 96.0 / settings.options.ppi.unwrap_or(144) as f64;
+        (pixels(16) * scale, pixels(20) * scale)
+    } else {
+        // Typst writes the view box in points
+        let svg = fs::read_to_string(path).ok()?;
+        let tag = &svg[svg.find("<svg")?..];
+        let tag = &tag[..tag.find('>')?];
+        let view_box = tag.split_once(r#"viewBox=""#)?.1.split('"').next()?;
+        let numbers = view_box
+            .split_whitespace()
+            .map(|n| n.parse::<f64>().ok())
+            .collect::<Option<Vec<_>>>()?;
+        let [_, _, width, height] = numbers[..] else {
+            return None;
+        };
+        (width * 4.0 / 3.0, height * 4.0 / 3.0)
+    };
+    Some((width.round() as u64, height.round() as u64))
+}
+
+/// Where a page gets a rendered image from: its file, or a data URI with `offline`
+fn image_src(name: &str, chapter_path: &Path, settings: &PreprocessSettings) -> String {
+    if !settings.offline {
+        return format!("{}/{name}", settings.img_dir);
+    }
+
+    data_uri(&chapter_path.join(&settings.img_dir).join(name))
+}
+
+/// An image file as a data URI
+fn data_uri(path: &Path) -> String {
+    let content = fs::read(path).unwrap_or_default();
+    if path.extension().is_some_and(|ext| ext == "png") {
+        return format!("data:image/png;base64,{}", BASE64_STANDARD.encode(content));
+    }
+
+    // SVG is text, so percent-encoding the few special characters is smaller than base64
+    let mut uri = String::from("data:image/svg+xml,");
+    for byte in content {
+        match byte {
+            b'A'..=b'Z'
+            | b'a'..=b'z'
+            | b'0'..=b'9'
+            | b' '
+            | b'-'
+            | b'_'
+            | b'.'
+            | b'/'
+            | b':'
+            | b'='
+            | b','
+            | b';'
+            | b'('
+            | b')' => uri.push(byte as char),
+            _ => uri += &format!("%{byte:02X}"),
+        }
+    }
+    uri
+}
+
+/// Styles for rendered images and classed highlighting, emitted once per chapter that has any.
+/// Image sizes are taken from CSS custom properties, which may be set in `css_vars`.
+fn stylesheet(settings: &PreprocessSettings, renders: bool) -> String {
+    let mut css = String::from("<style>\n");
+
+    if let Some(class_style) = settings.class_style {
+        // The stylesheet has no alphas, so colors are matched by their `#rrggbb`
+        let mut theme_css = css_for_theme_with_class_style(&settings.theme, class_style).unwrap();
+        for (color, var) in &settings.color_vars {
+            let hex = css_color(Color { a: 0xFF, ..*color }, &[]);
+            theme_css = theme_css.replace(&format!("{hex};"), &format!("{var};"));
+        }
+        css += &theme_css;
+    }
+    if settings.theme_dark.is_some() {
+        // mdBook's dark themes
+        css += ".typst-hl-dark { display: none; }
+.coal .typst-hl-light, .navy .typst-hl-light, .ayu .typst-hl-light { display: none; }
+.coal .typst-hl-dark, .navy .typst-hl-dark, .ayu .typst-hl-dark { display: revert; }
+";
+    }
+    if settings.options.line_numbers {
+        // Not selectable, so copying the code doesn't copy the numbers
+        css += ".typst-lines { display: flex; }
+.typst-lines > :last-child { flex: 1; min-width: 0; }
+.typst-line-numbers { white-space: pre; text-align: right; opacity: 0.5; user-select: none; }
+";
+    }
+    if renders {
+        css += &render_stylesheet(settings);
+    }
+    if renders && settings.options.print_css {
+        // Figures cut in half by a page break are unreadable
+        css += "@media print {
+.typst-render {
+    break-inside: avoid;
+    page-break-inside: avoid;
+}
+}
+";
+    }
+
+    css + "</style>\n"
+}
+
+fn render_stylesheet(settings: &PreprocessSettings) -> String {
+    let vars = settings
+        .css_vars
+        .iter()
+        .map(|(name, value)| format!("{name}: {value};"))
+        .collect::<String>();
+    let dark = if settings.options.experimental_dark_svg {
+        "@media (prefers-color-scheme: dark) {
+    .typst-render img, .typst-render svg, .typst-render-inline {
+        background: none;
+    }
+}
+"
+    } else {
+        ""
+    };
+
+    let dark_variants = if settings.dark_page_fill.is_some() {
+        // mdBook's dark themes, like for `theme_dark`
+        ".typst-render-dark { display: none; }
+.coal .typst-render-light, .navy .typst-render-light, .ayu .typst-render-light { display: none; }
+.coal .typst-render-dark, .navy .typst-render-dark, .ayu .typst-render-dark { display: revert; }
+.typst-render-dark img, .typst-render-dark svg { background: none; }
+"
+    } else {
+        ""
+    };
+
+    let max_width = &settings.image_max_width;
+    let align = &settings.image_align;
+
+    format!(
+        ".typst-render {{
+    {vars}
+    text-align: {align};
+    padding: 0.5em;
+    background: var(--quote-bg);
+}}
+.typst-render img, .typst-render svg {{
+    background: white;
+    max-width: var(--typst-img-maxwidth, {max_width});
+    width: 100%;
+    height: auto;
+}}
+.typst-render img {{
+    vertical-align: middle;
+}}
+.typst-background {{
+    padding: 0;
+    min-height: var(--typst-background-height, 300px);
+    background-size: var(--typst-background-size, cover);
+    background-position: var(--typst-background-position, center);
+    background-repeat: no-repeat;
+}}
+.typst-render-inline {{
+    vertical-align: middle;
+    background: white;
+}}
+.typst-preview {{
+    position: relative;
+}}
+.typst-preview-tooltip {{
+    display: none;
+    position: absolute;
+    left: 0;
+    top: 100%;
+    z-index: 10;
+    padding: 0.3em;
+    background: white;
+    box-shadow: 0 1px 4px rgba(0, 0, 0, 0.3);
+}}
+.typst-preview:hover .typst-preview-tooltip,
+.typst-preview:focus .typst-preview-tooltip {{
+    display: block;
+}}
+.typst-placeholder {{
+    font-style: italic;
+    opacity: 0.7;
+}}
+.typst-figure {{
+    margin: 0 0 0.5em;
+}}
+.typst-side-by-side {{
+    display: flex;
+    gap: 1em;
+    align-items: flex-start;
+}}
+.typst-side-by-side > div {{
+    flex: 1;
+    min-width: 0;
+}}
+@media (max-width: 700px) {{
+    .typst-side-by-side {{
+        flex-direction: column;
+    }}
+    .typst-side-by-side > div {{
+        width: 100%;
+    }}
+}}
+.typst-caption {{
+    text-align: center;
+    font-style: italic;
+    margin-top: 0.3em;
+}}
+.typst-source-link {{
+    text-align: right;
+    font-size: 0.8em;
+}}
+.typst-transparent img, .typst-transparent svg {{
+    background: none;
+}}
+.typst-pages-row {{
+    display: flex;
+    gap: 0.5em;
+    overflow-x: auto;
+}}
+.typst-pages-row img, .typst-pages-row svg {{
+    flex: 0 0 auto;
+    width: auto;
+}}
+.typst-pages-grid {{
+    display: grid;
+    grid-template-columns: repeat(auto-fill, minmax(200pt, 1fr));
+    gap: 0.5em;
+}}
+{dark}{dark_variants}"
+    )
+}
+
+/// Splits a first line like `// caption: Sales over time` off a block
+fn leading_caption(text: &str) -> Option<(&str, &str)> {
+    let (first, rest) = text.split_once('\n').unwrap_or((text, ""));
+    let caption = first.trim().strip_prefix("//")?.trim_start();
+    let caption = caption.strip_prefix("caption:")?.trim();
+    Some((caption, rest))
+}
+
+/// Removes whitespace-only lines at the start and the end, keeping the line endings of the rest
+fn trim_blank_lines(text: &str) -> String {
+    let mut lines = text
+        .split_inclusive('\n')
+        .skip_while(|line| line.trim().is_empty())
+        .collect::<Vec<_>>();
+    while lines.last().is_some_and(|line| line.trim().is_empty()) {
+        lines.pop();
+    }
+    lines.concat()
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('"', "&quot;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// Whether the code span between the last and the next event is surrounded by math delimiters,
+/// like `` $`x^2`$ ``
+fn is_inline_math(before: &[Event], after: Option<&Event>, delimiter: &str) -> bool {
+    !delimiter.is_empty()
+        && matches!(before.last(), Some(Event::Text(s)) if s.ends_with(delimiter))
+        && matches!(after, Some(Event::Text(s)) if s.starts_with(delimiter))
+}
+
+/// Highlights the same code with each of the given built-in themes, side by side
+fn theme_gallery(src: &str, themes: &[String], class_prefix: &str) -> Result<String> {
+    let mut html = String::from(r#"<div style="display: flex; flex-wrap: wrap; gap: 0.5em">"#);
+
+    for name in themes {
+        let theme = THEMES.themes.get(name).ok_or_else(|| {
+            anyhow!(
+                "Unknown theme \"{}\", available are: {}",
+                name,
+                THEMES.themes.keys().join(", ")
+            )
+        })?;
+        let color = |c: Option<Color>| {
+            c.map(|c| format!("#{:02x}{:02x}{:02x}", c.r, c.g, c.b))
+                .unwrap_or_else(|| "inherit".into())
+        };
+
+        html += &format!(
+            r#"<div style="flex: 1; min-width: 15em; padding: 0.5em; background: {}; color: {}"><div style="font-size: 0.8em; opacity: 0.7">{}</div>{}</div>"#,
+            color(theme.settings.background),
+            color(theme.settings.foreground),
+            name,
+            highlight(
+                src.to_owned().into(),
+                typst_syntax(),
+                theme,
+                false,
+                None,
+                &[],
+                class_prefix
+            )
+        );
+    }
+
+    Ok(html + "</div>")
+}
+
+/// mdbook's search index ignores the contents of `<script>`, so the html is stored in one
+/// and put back into the page by a tiny script. Requires JavaScript to be shown.
+fn hide_from_search(html: &str) -> String {
+    format!(
+        r#"<script type="text/html">{html}</script><script>(s => s.outerHTML = s.textContent)(document.currentScript.previousElementSibling)</script>"#
+    )
+}
+
+fn get_block_spec(
+    t: &Tag,
+    settings: &PreprocessSettings,
+    chapter: Option<&str>,
+) -> Option<BlockSpec> {
+    let default = || {
+        settings
+            .options
+            .typst_default
+            .then(|| BlockSpec::parse(&settings.languages[0]))
+            .flatten()
+    };
+    if let Tag::CodeBlock(ref kind) = *t {
+        match kind {
+            CodeBlockKind::Fenced(info) => BlockSpec::parse(info).or_else(|| {
+                if settings.options.warn_not_specified {
+                    if let Some(chapter) = chapter {
+                        warn!("Codeblock language not specified in {}", chapter)
+                    }
+                }
+                default()
+            }),
+            CodeBlockKind::Indented => default(),
+        }
+    } else {
+        None
+    }
+}
+
+/// Whether a Typst block of the book has a `query=`
+fn has_query_blocks(book: &Book, settings: &PreprocessSettings) -> bool {
+    book.iter().any(|item| match item {
+        BookItem::Chapter(chapter) => new_cmark_parser(&chapter.content, false).any(|event| {
+            let Event::Start(tag) = event else {
+                return false;
+            };
+            get_block_spec(&tag, settings, None).is_some_and(|spec| {
+                spec.is_typst(&settings.languages) && spec.attr("query").is_some()
+            })
+        }),
+        _ => false,
+    })
+}
+
+/// A `verbatim` block as it is written, from `source` starting with its opening fence and
+/// the events of its code up to its end. Written by the serializer, it would get another fence.
+fn verbatim_block<'a>(source: &str, events: &mut impl Iterator<Item = Event<'a>>) -> Event<'a> {
+    let fence = source.lines().next().unwrap_or_default().trim();
+    let marker = fence.chars().next().unwrap_or('`');
+    let closing = &fence[..fence.len() - fence.trim_start_matches(marker).len()];
+    let mut block = format!("{fence}\n");
+    for event in events.by_ref() {
+        match event {
+            Event::Text(text) => block += &text,
+            Event::End(_) => break,
+            _ => {}
+        }
+    }
+    // Html blocks only end at a blank line
+    Event::Html(format!("{block}{closing}\n\n").into())
+}
+
+fn typst_syntax() -> &'static SyntaxReference {
+    SYNTAX.syntaxes().last().unwrap()
+}
+
+/// Splits a `lang: code` prefix off an inline code span, e.g. `` `typ: #let x = 1` ``.
+/// Only the `languages` and the `inline_languages` are prefixes, so `` `sh: not found` `` or
+/// `` `C: drive` `` keep their text. Returns `None` if there is no prefix.
+fn split_inline_lang<'a>(
+    code: &'a str,
+    settings: &PreprocessSettings,
+) -> Option<(&'static SyntaxReference, &'a str)> {
+    let (lang, rest) = code.split_once(": ")?;
+    if lang.is_empty()
+        || !lang
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "+-_#".contains(c))
+    {
+        return None;
+    }
+
+    let syntax = if info_string::is_typst_lang(lang, &settings.languages) {
+        typst_syntax()
+    } else if settings.options.inline_languages.iter().any(|l| l == lang) {
+        SYNTAX.find_syntax_by_token(lang)?
+    } else {
+        return None;
+    };
+
+    Some((syntax, rest))
+}
+
+/// A place in a Typst file the bundled grammar doesn't seem to understand
+pub struct GrammarGap {
+    pub file: PathBuf,
+    /// 1-based line number
+    pub line: usize,
+    pub text: String,
+    /// `#` and `$` characters that got no scope, or the parsing error
+    pub problem: String,
+}
+
+/// Highlights all `.typ` files in `dir` (recursively) and reports lines where the grammar
+/// fails or leaves Typst syntax characters in the plain top-level scope.
+/// Plain markup text is expected to be unscoped, so only `#` and `$` (which start code and math) count.
+pub fn check_grammar(dir: &Path) -> Result<Vec<GrammarGap>> {
+    let mut files = vec![];
+    collect_typst_files(dir, &mut files)?;
+    files.sort();
+
+    let mut gaps = vec![];
+
+    for file in files {
+        let content = fs::read_to_string(&file)?;
+        let mut state = ParseState::new(typst_syntax());
+        let mut stack = ScopeStack::new();
+
+        for (n, line) in LinesWithEndings::from(&content).enumerate() {
+            let gap = |problem: String| GrammarGap {
+                file: file.clone(),
+                line: n + 1,
+                text: line.trim_end().to_owned(),
+                problem,
+            };
+
+            let ops = match state.parse_line(line, &SYNTAX) {
+                Ok(ops) => ops,
+                Err(err) => {
+                    gaps.push(gap(err.to_string()));
+                    // The state is broken, the rest of the file can't be trusted
+                    break;
+                }
+            };
+
+            let mut unscoped = String::new();
+            for (text, op) in ScopeRegionIterator::new(&ops, line) {
+                if let Err(err) = stack.apply(op) {
+                    unscoped = err.to_string();
+                    break;
+                }
+                if stack.len() <= 1 {
+                    unscoped.extend(text.chars().filter(|c| "#$".contains(*c)));
+                }
+            }
+
+            if !unscoped.is_empty() {
+                gaps.push(gap(unscoped));
+            }
+        }
+    }
+
+    Ok(gaps)
+}
+
+fn collect_typst_files(dir: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect_typst_files(&path, files)?;
+        } else if path.extension().is_some_and(|ext| ext == "typ") {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Highlighted code by syntax name, code, and whether it's inline
+type HighlightKey = (String, String, bool);
+
+fn highlight_cached(
+    code: &str,
+    syntax: &SyntaxReference,
+    inline: bool,
+    settings: &PreprocessSettings,
+) -> String {
+    let key = (syntax.name.clone(), code.to_owned(), inline);
+    match settings.prehighlighted.get(&key) {
+        Some(html) => html.clone(),
+        None => highlight_themed(code, syntax, inline, settings),
+    }
+}
+
+/// Highlights with the book theme, or with both `theme_light` and `theme_dark`,
+/// of which the stylesheet shows one
+fn highlight_themed(
+    code: &str,
+    syntax: &SyntaxReference,
+    inline: bool,
+    settings: &PreprocessSettings,
+) -> String {
+    let highlight = |theme| {
+        highlight(
+            code.to_owned().into(),
+            syntax,
+            theme,
+            inline,
+            settings.class_style,
+            &settings.color_vars,
+            &settings.class_prefix,
+        )
+    };
+    let Some(dark) = &settings.theme_dark else {
+        return highlight(&settings.theme);
+    };
+
+    let tag = if inline { "span" } else { "div" };
+    format!(
+        r#"<{tag} class="typst-hl-light">{}</{tag}><{tag} class="typst-hl-dark">{}</{tag}>"#,
+        highlight(&settings.theme),
+        highlight(dark)
+    )
+}
+
+/// Highlights the code of all chapters on `highlight_threads` threads, before they are
+/// processed one by one. Highlighting doesn't depend on the order, so the output is the same;
+/// anything missed here is highlighted when its chapter is processed.
+fn prehighlight(
+    book: &Book,
+    settings: &PreprocessSettings,
+) -> Result<HashMap<HighlightKey, String>> {
+    let mut jobs = HashMap::new();
+    for item in book.iter() {
+        if let BookItem::Chapter(chapter) = item {
+            collect_highlights(&chapter.content, settings, &mut jobs);
+        }
+    }
+
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(settings.highlight_threads)
+        .build()?;
+    Ok(pool.install(|| {
+        jobs.into_par_iter()
+            .map(|(key, syntax)| {
+                let html = highlight_themed(&key.1, syntax, key.2, settings);
+                (key, html)
+            })
+            .collect()
+    }))
+}
+
+/// Finds the code `process_chapter` highlights, following the same rules
+fn collect_highlights(
+    content: &str,
+    settings: &PreprocessSettings,
+    jobs: &mut HashMap<HighlightKey, &'static SyntaxReference>,
+) {
+    let mut add = |code: &str, syntax: &'static SyntaxReference, inline: bool| {
+        jobs.insert((syntax.name.clone(), code.to_owned(), inline), syntax);
+    };
+    let is_typst = |tag: &Tag| {
+        get_block_spec(tag, settings, None).is_some_and(|s| s.is_typst(&settings.languages))
+    };
+
+    let mut events = new_cmark_parser(content, false).peekable();
+    let mut codeblock_text: Option<String> = None;
+    let mut last = None;
+    while let Some(event) = events.next() {
+        match &event {
+            Event::Start(tag) if is_typst(tag) => codeblock_text = Some(String::new()),
+            Event::End(tag) if is_typst(tag) => {
+                if let Some(text) = codeblock_text.take() {
+                    add(&block_source(text, settings).0, typst_syntax(), false);
+                }
+            }
+            Event::Text(s) => {
+                if let Some(text) = &mut codeblock_text {
+                    push_block_text(text, s, settings)
+                }
+            }
+            Event::Code(_) if !settings.highlight_inline => {}
+            Event::Code(code)
+                if settings
+                    .max_inline_length
+                    .is_some_and(|max| code.chars().count() > max) => {}
+            Event::Code(code)
+                if is_inline_math(
+                    Option::as_slice(&last),
+                    events.peek(),
+                    &settings.math_delimiter,
+                ) =>
+            {
+                add(&format!("${code}$"), typst_syntax(), true)
+            }
+            Event::Code(code) if settings.math_prefix && code.starts_with(MATH_PREFIX) => add(
+                &format!("${}$", &code[MATH_PREFIX.len()..]),
+                typst_syntax(),
+                true,
+            ),
+            Event::Code(code) if settings.inline_preview && code.starts_with(PREVIEW_PREFIX) => {
+                add(&code[PREVIEW_PREFIX.len()..], typst_syntax(), true)
+            }
+            Event::Code(code) => match split_inline_lang(code, settings) {
+                Some((syntax, code)) => add(code, syntax, true),
+                None if !settings.options.inline_require_lang => add(code, typst_syntax(), true),
+                None => {}
+            },
+            _ => {}
+        }
+        last = Some(event);
+    }
+}
+
+/// Puts a column with line numbers beside a highlighted block. It isn't a `<pre>`, which
+/// would get mdBook's copy button, but its `<code>` is styled like the highlighted one.
+fn with_line_numbers(html: &str, code: &str, class_prefix: &str) -> String {
+    // `highlight` drops the final line ending
+    let lines = code.strip_suffix('\n').unwrap_or(code).split('\n').count();
+    let numbers = (1..=lines).join("\n");
+    format!(
+        r#"<div class="typst-lines"><div class="typst-line-numbers" aria-hidden="true"><code class="{class_prefix}hljs">{numbers}</code></div><div>{html}</div></div>"#
+    )
+}
+
+/// Appends text of a code block as it was read
+fn push_block_text(text: &mut String, s: &str, settings: &PreprocessSettings) {
+    // Keep hashes and output the same for sources written on Windows
+    if settings.options.keep_line_endings {
+        text.push_str(s)
+    } else {
+        text.push_str(&s.replace("\r\n", "\n"))
+    }
+}
+
+/// Splits the text of a Typst block into the shown code, the compiled source, the caption
+/// comment, if any, and the number of lines before the compiled source, like blank ones
+fn block_source(
+    text: String,
+    settings: &PreprocessSettings,
+) -> (String, String, Option<String>, usize) {
+    // Before hashing, so padding doesn't create new renders
+    let (text, blank_lines) = if settings.options.trim_block {
+        let blank_lines = text
+            .split_inclusive('\n')
+            .take_while(|line| line.trim().is_empty())
+            .count();
+        (trim_blank_lines(&text), blank_lines)
+    } else {
+        (text, 0)
+    };
+
+    let (comment_caption, source) = match leading_caption(&text) {
+        Some((caption, source)) => (Some(caption.to_owned()), source.to_owned()),
+        None => (None, text.clone()),
+    };
+    let skipped_lines = blank_lines + usize::from(comment_caption.is_some());
+    // The comment is never compiled, as the caption is shown anyway
+    let shown = if settings.options.keep_caption_comment {
+        text
+    } else {
+        source.clone()
+    };
+
+    if settings.options.hide_lines {
+        (
+            hide_lines(&shown, false),
+            hide_lines(&source, true),
+            comment_caption,
+            skipped_lines,
+        )
+    } else {
+        (shown, source, comment_caption, skipped_lines)
+    }
+}
+
+/// Handles lines hidden like in mdBook's Rust blocks: a line that starts with `# ` (or is
+/// just `#`) is only compiled, without the marker. `##` at the start is a literal `#`.
+fn hide_lines(text: &str, compiled: bool) -> String {
+    text.split_inclusive('\n')
+        .filter_map(|line| {
+            let content = line.trim_start();
+            let indent = &line[..line.len() - content.len()];
+            if let Some(rest) = content.strip_prefix("##") {
+                Some(format!("{indent}#{rest}"))
+            } else if content.trim_end() == "#" {
+                compiled.then(|| format!("{indent}{}", &content[1..]))
+            } else if let Some(rest) = content.strip_prefix("# ") {
+                compiled.then(|| format!("{indent}{rest}"))
+            } else {
+                Some(line.to_owned())
+            }
+        })
+        .collect()
+}
+
+/// Highlights inline code. Short spans like `#let` are repeated all over a book,
+/// so the results are cached.
+fn highlight_inline(code: &str, syntax: &SyntaxReference, settings: &PreprocessSettings) -> String {
+    let highlight = || highlight_cached(code, syntax, true, settings);
+
+    let Some(cache) = &settings.inline_cache else {
+        return highlight();
+    };
+    let mut cache = cache.lock().unwrap();
+    let key = (syntax.name.clone(), code.to_owned());
+
+    cache.get_or_insert(key, highlight).clone()
+}
+
+fn highlight(
+    s: CowStr,
+    syntax: &SyntaxReference,
+    theme: &Theme,
+    inline: bool,
+    class_style: Option<ClassStyle>,
+    color_vars: &[(Color, String)],
+    class_prefix: &str,
+) -> String {
+    let mut s = s.into_string();
+    if s.ends_with('\n') {
+        s.pop();
+    }
+
+    if let Some(class_style) = class_style {
+        let mut generator =
+            ClassedHTMLGenerator::new_with_class_style(syntax, &SYNTAX, class_style);
+        for line in LinesWithEndings::from(&s) {
+            generator
+                .parse_html_for_line_which_includes_newline(line)
+                .unwrap();
+        }
+        if inline {
+            // The grammar needs line endings, but inline code can't have them
+            generator
+                .parse_html_for_line_which_includes_newline("\n")
+                .unwrap();
+        }
+        let html = generator.finalize();
+
+        if inline {
+            format!(
+                r#"<code class="{class_prefix}hljs">{}</code>"#,
+                html.replace('\n', "")
+            )
+        } else {
+            format!(
+                r#"<pre style="margin: 0"><code class="language-{class_prefix}typ {class_prefix}hljs">{}</code></pre>
+"#,
+                html
+            )
+        }
+    } else if inline {
+        let mut h = HighlightLines::new(syntax, theme);
+        let regs = h.highlight_line(s.as_ref(), &SYNTAX).unwrap(); // everything should be fine
+        let mut html = String::new();
+        append_styled_html(&regs[..], color_vars, &mut html);
+        format!(r#"<code class="{class_prefix}hljs">{}</code>"#, html)
+    } else {
+        let mut html = format!(
+            r#"<pre style="margin: 0"><code class="language-{class_prefix}typ {class_prefix}hljs">"#
+        );
+
+        let mut highlighter = HighlightLines::new(syntax, theme);
+
+        for line in LinesWithEndings::from(&s) {
+            let regions = highlighter.highlight_line(line, &SYNTAX).unwrap();
+            append_styled_html(&regions[..], color_vars, &mut html);
+        }
+
+        html.push_str("</code></pre>\n");
+
+        html
+    }
+}
+
+fn sha256_hash(input: &str) -> String {
+    let mut res = Sha256::new();
+    res.update(input.as_bytes());
+    let res = res.finalize();
+    format!("{:x}", res)
+}
+
+fn get_images(src: PathBuf) -> impl Iterator<Item = String> {
+    let mut n = 1;
+    let fbase = src.file_name().unwrap().to_str().unwrap().to_owned();
+    // The hash includes the format, so a block only has images of one
+    let extension = if src.with_file_name(fbase.clone() + "-1.png").exists() {
+        "png"
+    } else {
+        "svg"
+    };
+
+    iter::from_fn(move || {
+        let path = src.clone();
+        let path = path.with_file_name(format!("{fbase}-{n}.{extension}"));
+
+        if path.exists() {
+            n += 1;
+            Some(path.file_name().unwrap().to_string_lossy().into_owned())
+        } else {
+            None
+        }
+    })
+    .fuse()
+}
+
+/// Typst names the images of selected pages by their page numbers, like `<hash>-3.svg`.
+/// Renames them to `<hash>-1.svg` and so on, as expected by `get_images`.
+fn renumber_pages(src: &Path, extension: &str) -> Result<()> {
+    let prefix = src.file_name().unwrap().to_string_lossy().into_owned() + "-";
+    let pages = fs::read_dir(src.parent().unwrap())?
+        .filter_map(|entry| {
+            let name = entry.ok()?.file_name().into_string().ok()?;
+            name.strip_prefix(&prefix)?
+                .strip_suffix(&format!(".{extension}"))?
+                .parse::<usize>()
+                .ok()
+        })
+        .sorted();
+
+    for (n, page) in pages.enumerate() {
+        if n + 1 != page {
+            fs::rename(
+                src.with_file_name(format!("{prefix}{page}.{extension}")),
+                src.with_file_name(format!("{prefix}{}.{extension}", n + 1)),
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// Styles that recolor a Typst SVG when the system prefers dark themes: black becomes light,
+/// and the white page background disappears. Other colors are kept.
+static DARK_SVG_STYLE: &str = r##"<style>@media (prefers-color-scheme: dark) {
+.typst-doc > .typst-shape:first-child[fill="#ffffff"] { fill: none }
+.typst-doc [fill="#000000"] { fill: #e0e0e0 }
+.typst-doc [stroke="#000000"] { stroke: #e0e0e0 }
+}</style>"##;
+
+fn add_dark_svg_style(path: &Path) -> Result<()> {
+    let svg = fs::read_to_string(path)?;
+    // Right after the opening tag, so the rules apply to the whole image
+    let end = svg
+        .find("<svg")
+        .and_then(|start| svg[start..].find('>').map(|end| start + end + 1))
+        .ok_or(anyhow!("no <svg> tag"))?;
+    let svg = svg[..end].to_owned() + DARK_SVG_STYLE + &svg[end..];
+    Ok(fs::write(path, svg)?)
+}
+
+/// Re-encodes a PNG image with the ICC profile of `color_profile`
+fn embed_color_profile(path: &Path, profile: &[u8]) -> Result<()> {
+    use image::ImageEncoder;
+
+    let decoded = image::load_from_memory_with_format(&fs::read(path)?, image::ImageFormat::Png)?;
+    let mut png = vec![];
+    let mut encoder = image::codecs::png::PngEncoder::new(&mut png);
+    encoder.set_icc_profile(profile.to_vec())?;
+    encoder.write_image(
+        decoded.as_bytes(),
+        decoded.width(),
+        decoded.height(),
+        decoded.color().into(),
+    )?;
+    fs::write(path, png)?;
+    Ok(())
+}
+
+/// Waits until at least `min_free` bytes of memory are available.
+/// Never waits when no other compilation is running, as nothing would free the memory then.
+async fn wait_for_memory(min_free: u64, state: &BuildState) {
+    let mut system = System::new();
+
+    loop {
+        system.refresh_memory();
+        let running = state.running_compiles.load(Ordering::SeqCst);
+        if system.available_memory() >= min_free || running == 0 {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(200)).await;
+    }
+}
+
+/// Settings of a single block, as given in its codeblock language
+#[derive(Default, Clone)]
+struct BlockOptions<'a> {
+    preamble: Option<&'a str>,
+    /// Content of the block's `preamble=` file, added after the preamble
+    block_preamble: Option<String>,
+    /// `--root` of the block, if it isn't the chapter directory
+    root: Option<PathBuf>,
+    /// Makes randomized examples reproducible, see `seed` in the README
+    seed: Option<String>,
+    /// Render without the white page background
+    transparent: bool,
+    /// Typst's `--pages` selection, like `1-2`
+    pages: Option<String>,
+    /// Page width from `width=`, instead of the preamble's
+    width: Option<String>,
+    /// The dark variant of the block, see `render_dark`
+    dark: bool,
+}
+
+/// How a rendered block is shown, until its images are inserted
+struct BlockDisplay {
+    /// Files used by the block
+    deps: Vec<PathBuf>,
+    background: bool,
+    transparent: bool,
+    caption: Option<String>,
+    alt: Option<String>,
+    /// Output path of the dark variant, see `render_dark`
+    dark: Option<PathBuf>,
+}
+
+/// A block scheduled for rendering
+struct RenderedBlock {
+    /// Output path without the page number and extension
+    path: PathBuf,
+    /// Files the block reads
+    deps: Vec<PathBuf>,
+    compile: Option<LocalBoxFuture<'static, Result<()>>>,
+}
+
+fn render_block(
+    src: String,
+    options: &BlockOptions,
+    dir: PathBuf,
+    build_dir: PathBuf,
+    origin: BlockOrigin,
+    settings: &PreprocessSettings,
+) -> Result<RenderedBlock> {
+    let BlockOrigin {
+        chapter: name,
+        line,
+    } = origin;
+    let (source_dir, root) = source_location(&dir, &build_dir, options);
+    let deps = dependencies(&src, &source_dir, &root, &settings.src_dir);
+    let source = compiled_source(&src, options, settings);
+    let lines = line.map(|line| BlockLines::new(&source, &src, line, settings));
+    let filename = block_hash(
+        &source,
+        &format!(
+            "{}{}{}{}",
+            deps_hash(&deps),
+            format_hash(settings),
+            args_hash(settings),
+            options_hash(options)
+        ),
+        settings,
+    );
+    settings
+        .state
+        .used_hashes
+        .lock()
+        .unwrap()
+        .insert(filename.clone());
+    let mut output = dir.clone();
+    output.push(&settings.img_dir);
+    let mut check = output.clone();
+    let mut cut_output = output.clone();
+    cut_output.push(filename.clone());
+
+    let extension = settings.format.extension();
+    output.push(format!("{filename}-{{n}}.{extension}"));
+    check.push(format!("{filename}-1.{extension}"));
+
+    let mut command = None;
+
+    let cached = check.exists();
+    if !cached
+        && settings
+            .state
+            .compiled_outputs
+            .lock()
+            .unwrap()
+            .insert(check.clone())
+    {
+        let images_dir = output.parent().unwrap();
+        fs::create_dir_all(images_dir).map_err(|err| write_error(images_dir, err))?;
+        let source = write_source(&source, &source_dir, &filename, settings)?;
+
+        // Typst writes the images under a unique name, they are moved to the cache when done
+        let temp = temp_path(&cut_output);
+        let temp_name = temp.file_name().unwrap().to_string_lossy().into_owned();
+
+        let compile = match (&settings.remote, &settings.library) {
+            #[cfg(feature = "remote")]
+            (Some(remote), _) => {
+                let page = temp.with_file_name(format!("{temp_name}-1.{extension}"));
+                let state = settings.state.clone();
+                let request =
+                    remote::compile(remote.clone(), source.clone(), page, name.clone(), state);
+                in_compile_slot(|| request, true, name, filename.clone(), settings)
+                    .and_then(futures::future::ready)
+                    .boxed_local()
+            }
+            #[cfg(feature = "library")]
+            (_, Some(library)) => {
+                let library = library.clone();
+                let (source, root, temp) = (source.clone(), root.clone(), temp.clone());
+                let seed = options.seed.clone();
+                let pages = options.pages.clone();
+                let compile = move || {
+                    let (seed, pages) = (seed.as_deref(), pages.as_deref());
+                    library::compile(&library, &source, &root, seed, pages, &temp)
+                };
+                run_in_process(compile, name, filename.clone(), lines, settings).boxed_local()
+            }
+            _ => {
+                let mut res = typst_command("c", &source, &root, options, settings);
+                res.arg(temp.with_file_name(format!("{temp_name}-{{n}}.{extension}")));
+                if let Some(pages) = &options.pages {
+                    res.arg("--pages").arg(pages);
+                }
+                if let Some(ppi) = settings.options.ppi {
+                    res.arg("--ppi").arg(ppi.to_string());
+                }
+
+                run_typst(res, name, filename.clone(), lines, settings)
+                    .map_ok(|_| ())
+                    .boxed_local()
+            }
+        };
+
+        let compile = without_source(compile, source, settings);
+
+        let renumber = options.pages.is_some();
+        let dark = settings.options.experimental_dark_svg;
+        let profile = settings.color_profile.clone();
+        let images = cut_output.clone();
+        let state = settings.state.clone();
+        command = Some(
+            compile
+                .map(move |res| {
+                    if res.is_err() {
+                        remove_temp_images(&temp);
+                        return res;
+                    }
+                    if renumber {
+                        state.postprocessed(renumber_pages(&temp, extension), &images);
+                    }
+                    for name in get_images(temp.clone()) {
+                        // Errors name the image as it ends up in the cache
+                        let cached = images
+                            .with_file_name(format!("{filename}{}", &name[temp_name.len()..]));
+                        let image = temp.with_file_name(name);
+                        if dark {
+                            state.postprocessed(add_dark_svg_style(&image), &cached);
+                        }
+                        if let Some(profile) = &profile {
+                            state.postprocessed(embed_color_profile(&image, profile), &cached);
+                        }
+                    }
+                    publish_images(&temp, &images)
+                })
+                .boxed_local(),
+        );
+    } else if cached && settings.options.source_link {
+        // The image is cached, but the linked source may have been cleaned up
+        write_source(&source, &source_dir, &filename, settings)?;
+    }
+
+    Ok(RenderedBlock {
+        path: cut_output,
+        deps,
+        compile: command,
+    })
+}
+
+/// Where the source of a block is written (in `src_dir` of the first path), and its root.
+/// Without a `root=`, the root is the book source directory, however deep the chapter is.
+fn source_location(dir: &Path, build_dir: &Path, options: &BlockOptions) -> (PathBuf, PathBuf) {
+    match &options.root {
+        // Typst only compiles files inside the root
+        Some(root) if !dir.starts_with(root) => (root.clone(), root.clone()),
+        Some(root) => (dir.to_path_buf(), root.clone()),
+        None => (dir.to_path_buf(), build_dir.to_path_buf()),
+    }
+}
+
+/// Renders one image tiling all the given images of a chapter
+fn render_contact_sheet(
+    images: &[String],
+    dir: PathBuf,
+    build_dir: PathBuf,
+    name: String,
+    settings: &PreprocessSettings,
+) -> Result<RenderedBlock> {
+    let cells = images
+        .iter()
+        .map(|image| {
+            format!(
+                "  image(\"../{}/{image}\", width: 150pt),\n",
+                settings.img_dir
+            )
+        })
+        .collect::<String>();
+    let src = format!(
+        "#set page(width: auto, height: auto, margin: 0.5cm)\n#grid(columns: 3, gutter: 0.5cm,\n{cells})\n"
+    );
+
+    render_block(
+        src,
+        &BlockOptions::default(),
+        dir,
+        build_dir,
+        BlockOrigin {
+            chapter: name,
+            line: None,
+        },
+        settings,
+    )
+}
+
+/// Where a block comes from, for reporting its errors
+struct BlockOrigin {
+    /// Name of the chapter
+    chapter: String,
+    /// Line of the chapter where the code starts, if it comes from the chapter's Markdown
+    line: Option<usize>,
+}
+
+/// Where the code of a block is in its compiled source and in its chapter, for errors
+#[derive(Clone, Copy)]
+struct BlockLines {
+    /// Lines of the compiled source before the code, like the preamble
+    before: usize,
+    /// Line of the chapter where the code starts
+    chapter_line: usize,
+}
+
+impl BlockLines {
+    /// `source` is the compiled source of `src`, which ends with it
+    fn new(source: &str, src: &str, chapter_line: usize, settings: &PreprocessSettings) -> Self {
+        let header = settings
+            .options
+            .source_header
+            .as_deref()
+            .map_or(0, |h| h.lines().count());
+        let prefix = &source[..source.len() - src.len()];
+        BlockLines {
+            before: header + prefix.matches('\n').count(),
+            chapter_line,
+        }
+    }
+
+    /// Adds the line of the block and of the chapter to every location Typst gives in the
+    /// block's source, like `typst-src/<hash>.typ:12:3`. The line numbers Typst shows are
+    /// those of the compiled source, which starts with the preamble.
+    fn annotate(&self, stderr: &str, block: &str) -> String {
+        let pattern = format!("{block}.typ:");
+        stderr
+            .split_inclusive('\n')
+            .map(|line| {
+                let Some(start) = line.find(&pattern) else {
+                    return line.to_owned();
+                };
+                let number = line[start + pattern.len()..]
+                    .split(|c: char| !c.is_ascii_digit())
+                    .next()
+                    .and_then(|n| n.parse::<usize>().ok());
+                let note = match number {
+                    Some(n) if n > self.before => {
+                        let n = n - self.before;
+                        format!(
+                            " (line {n} of the block, around line {} of the chapter)",
+                            self.chapter_line + n - 1
+                        )
+                    }
+                    Some(_) => " (in the preamble, before the block)".to_owned(),
+                    None => return line.to_owned(),
+                };
+                let end = line.trim_end_matches(['\n', '\r']).len();
+                format!("{}{note}{}", &line[..end], &line[end..])
+            })
+            .collect()
+    }
+}
+
+/// Runs `typst query` with the given selector on a block. The result is written as JSON
+/// to `<img_dir>/<hash>.json`, which is the returned path.
+fn query_block(
+    src: String,
+    selector: &str,
+    options: &BlockOptions,
+    dir: PathBuf,
+    build_dir: PathBuf,
+    origin: BlockOrigin,
+    settings: &PreprocessSettings,
+) -> Result<(PathBuf, Option<LocalBoxFuture<'static, Result<()>>>)> {
+    let BlockOrigin {
+        chapter: name,
+        line,
+    } = origin;
+    let (source_dir, root) = source_location(&dir, &build_dir, options);
+    let deps = dependencies(&src, &source_dir, &root, &settings.src_dir);
+    let source = compiled_source(&src, options, settings);
+    let lines = line.map(|line| BlockLines::new(&source, &src, line, settings));
+    let filename = block_hash(
+        &source,
+        &format!(
+            "query:{selector}{}{}{}",
+            deps_hash(&deps),
+            args_hash(settings),
+            options_hash(options)
+        ),
+        settings,
+    );
+    settings
+        .state
+        .used_hashes
+        .lock()
+        .unwrap()
+        .insert(filename.clone());
+    let mut output = dir.clone();
+    output.push(&settings.img_dir);
+    output.push(filename.clone() + ".json");
+
+    let mut command = None;
+
+    if !output.exists()
+        && settings
+            .state
+            .compiled_outputs
+            .lock()
+            .unwrap()
+            .insert(output.clone())
+    {
+        let results_dir = output.parent().unwrap();
+        fs::create_dir_all(results_dir).map_err(|err| write_error(results_dir, err))?;
+        let source = write_source(&source, &source_dir, &filename, settings)?;
+
+        let mut res = typst_command("query", &source, &root, options, settings);
+        res.arg(selector);
+
+        let json = output.clone();
+        let query = run_typst(res, name, filename.clone(), lines, settings)
+            .and_then(move |stdout| {
+                futures::future::ready(
+                    write_atomic(&json, stdout).map_err(|err| write_error(&json, err)),
+                )
+            })
+            .boxed_local();
+        command = Some(without_source(query, source, settings));
+    }
+
+    Ok((output, command))
+}
+
+/// Removes the source after a successful compilation, unless `keep_typ_source`.
+/// A failed block keeps it, to look at what was compiled.
+fn without_source(
+    compile: LocalBoxFuture<'static, Result<()>>,
+    source: PathBuf,
+    settings: &PreprocessSettings,
+) -> LocalBoxFuture<'static, Result<()>> {
+    if settings.options.keep_typ_source {
+        return compile;
+    }
+    // The same block may be compiled twice at once, from the same source
+    let state = settings.state.clone();
+    *state
+        .sources_in_use
+        .lock()
+        .unwrap()
+        .entry(source.clone())
+        .or_default() += 1;
+    compile
+        .map(move |res| {
+            let mut in_use = state.sources_in_use.lock().unwrap();
+            let count = in_use.get_mut(&source).unwrap();
+            *count -= 1;
+            if *count == 0 {
+                in_use.remove(&source);
+                if res.is_ok() {
+                    if let Err(err) = fs::remove_file(&source) {
+                        warn!("Can't remove {}: {}", source.display(), err);
+                    }
+                }
+            }
+            res
+        })
+        .boxed_local()
+}
+
+/// Numbers the temporary files of this process
+static TEMP_FILES: AtomicUsize = AtomicUsize::new(0);
+
+/// A unique path next to `path`, hidden and not named like a cached file, to write it before
+/// renaming. Renaming is atomic, so other builds never see a half-written file, even if they
+/// compile the same block at the same time.
+fn temp_path(path: &Path) -> PathBuf {
+    let name = path.file_name().unwrap().to_string_lossy();
+    path.with_file_name(format!(
+        ".{name}.{}-{}",
+        std::process::id(),
+        TEMP_FILES.fetch_add(1, Ordering::Relaxed)
+    ))
+}
+
+fn write_atomic(path: &Path, contents: impl AsRef<[u8]>) -> std::io::Result<()> {
+    let temp = temp_path(path);
+    fs::write(&temp, contents)?;
+    fs::rename(temp, path)
+}
+
+/// Moves the images of a compilation from their temporary names to `output`, like
+/// `<hash>-1.svg`. The first page is moved last, as the cache is checked by it.
+fn publish_images(temp: &Path, output: &Path) -> Result<()> {
+    let temp_name = temp.file_name().unwrap().to_string_lossy().into_owned();
+    let output_name = output.file_name().unwrap().to_string_lossy().into_owned();
+    let names = get_images(temp.to_path_buf()).collect::<Vec<_>>();
+    for name in names.iter().rev() {
+        let page = &name[temp_name.len()..];
+        fs::rename(
+            temp.with_file_name(name),
+            output.with_file_name(output_name.clone() + page),
+        )?;
+    }
+    Ok(())
+}
+
+/// Removes whatever a failed compilation managed to write
+fn remove_temp_images(temp: &Path) {
+    let prefix = temp.file_name().unwrap().to_string_lossy().into_owned() + "-";
+    for entry in fs::read_dir(temp.parent().unwrap())
+        .into_iter()
+        .flatten()
+        .flatten()
+    {
+        if entry.file_name().to_string_lossy().starts_with(&prefix) {
+            let _ = fs::remove_file(entry.path());
+        }
+    }
+}
+
+/// Removes files of unused blocks from the cache directories anywhere inside `dir`.
+/// Only files named like the preprocessor names them are touched. Returns their number.
+fn prune_unused(dir: &Path, used: &BTreeSet<String>, settings: &PreprocessSettings) -> usize {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return 0;
+    };
+    let mut removed = 0;
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if !path.is_dir() {
+            continue;
+        }
+        let name = entry.file_name();
+        if name != settings.img_dir.as_str() && name != settings.src_dir.as_str() {
+            removed += prune_unused(&path, used, settings);
+            continue;
+        }
+        for file in fs::read_dir(&path).into_iter().flatten().flatten() {
+            let file_name = file.file_name();
+            let Some(hash) = file_name.to_str().and_then(cached_file_hash) else {
+                continue;
+            };
+            if !used.contains(hash) && fs::remove_file(file.path()).is_ok() {
+                removed += 1;
+            }
+        }
+    }
+    removed
+}
+
+/// The block hash of a file written by `render_block` or `query_block`, like
+/// `<hash>-1.svg`, `<hash>.json` or `<hash>.typ`
+fn cached_file_hash(name: &str) -> Option<&str> {
+    let hash = name.get(..64)?;
+    if !hash.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f')) {
+        return None;
+    }
+    let rest = &name[64..];
+    let page = rest
+        .strip_prefix('-')
+        .and_then(|rest| rest.strip_suffix(".svg").or(rest.strip_suffix(".png")))
+        .is_some_and(|n| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()));
+    (page || rest == ".typ" || rest == ".json").then_some(hash)
+}
+
+/// Files a block may read: every string literal in it that names an existing file.
+/// Relative paths are resolved from `src_dir`, where the block is compiled.
+fn dependencies(src: &str, dir: &Path, root: &Path, src_dir: &str) -> Vec<PathBuf> {
+    let mut deps = vec![];
+    let mut rest = src;
+
+    while let Some(start) = rest.find('"') {
+        rest = &rest[start + 1..];
+        let mut end = 0;
+        let mut escaped = false;
+        for (i, c) in rest.char_indices() {
+            match c {
+                '\\' => escaped = !escaped,
+                '"' if !escaped => {
+                    end = i;
+                    break;
+                }
+                _ => escaped = false,
+            }
+            end = i + c.len_utf8();
+        }
+
+        let literal = &rest[..end];
+        rest = &rest[(end + 1).min(rest.len())..];
+
+        if literal.is_empty() || literal.starts_with('@') {
+            continue;
+        }
+        let path = match literal.strip_prefix('/') {
+            Some(absolute) => root.join(absolute),
+            None => dir.join(src_dir).join(literal),
+        };
+        // `src_dir` may not exist yet, so `..` can't be left to the file system
+        let path = path.components().fold(PathBuf::new(), |mut path, c| {
+            match c {
+                Component::ParentDir => {
+                    path.pop();
+                }
+                c => path.push(c),
+            }
+            path
+        });
+        if path.is_file() && !deps.contains(&path) {
+            deps.push(path);
+        }
+    }
+
+    deps
+}
+
+/// Part of the hash for the files a block reads. Editing one must compile the block again,
+/// though its source is the same.
+fn deps_hash(deps: &[PathBuf]) -> String {
+    deps.iter()
+        .filter_map(|dep| fs::read(dep).ok())
+        .map(|content| format!("{:x}", Sha256::digest(content)))
+        .collect()
+}
+
+/// Name of the cached files of a block. `extra` describes the way the block is processed.
+fn block_hash(src: &str, extra: &str, settings: &PreprocessSettings) -> String {
+    // The context is imported by every block, so its changes must invalidate them all
+    let context = settings.context.as_deref().unwrap_or("");
+    // The images are changed after compiling
+    let dark = if settings.options.experimental_dark_svg {
+        "dark-svg"
+    } else {
+        ""
+    };
+    sha256_hash(&(src.to_owned() + context + extra + dark))
+}
+
+/// Part of the hash for the image format, as its output differs for the same source
+fn format_hash(settings: &PreprocessSettings) -> String {
+    let hash = match (settings.format, settings.options.ppi) {
+        (ImageFormat::Png, Some(ppi)) => format!("png:{ppi}"),
+        (format, _) => format.extension().to_owned(),
+    };
+    match &settings.color_profile {
+        Some(profile) => format!("{hash}icc:{:x}", Sha256::digest(profile)),
+        None => hash,
+    }
+}
+
+/// Part of the hash for `typst_args` and `inputs`, empty without them
+fn args_hash(settings: &PreprocessSettings) -> String {
+    if settings.typst_args.is_empty() {
+        return String::new();
+    }
+    format!("args:{:?}", settings.typst_args)
+}
+
+/// Part of the hash for the options of a block that are passed to Typst as arguments.
+/// The others are part of `compiled_source`.
+fn options_hash(options: &BlockOptions) -> String {
+    let mut hash = options
+        .seed
+        .as_deref()
+        .map(|seed| format!("seed:{seed}"))
+        .unwrap_or_default();
+    if let Some(pages) = &options.pages {
+        hash += &format!("pages:{pages}");
+    }
+    hash
+}
+
+/// Text color of the dark variants of blocks, light like the text of mdBook's dark themes
+static DARK_TEXT_FILL: &str = "#dcdcdc";
+
+/// The source of a block as it is compiled, with the preamble and the options that are
+/// set in Typst. Cached files are named by its hash, so a changed preamble renders again.
+fn compiled_source(src: &str, options: &BlockOptions, settings: &PreprocessSettings) -> String {
+    let mut source = String::new();
+    if let Some(preamble) = options.preamble {
+        source += preamble;
+        source.push('\n');
+    };
+    if let Some(preamble) = &options.block_preamble {
+        source += preamble;
+        source.push('\n');
+    }
+    if let Some(fill) = settings.dark_page_fill.as_ref().filter(|_| options.dark) {
+        if !options.transparent {
+            source += &format!("#set page(fill: rgb(\"{fill}\"))\n");
+        }
+        source += &format!("#set text(fill: rgb(\"{DARK_TEXT_FILL}\"))\n");
+    }
+    if options.transparent {
+        source += "#set page(fill: none)\n";
+    }
+    if let Some(width) = &options.width {
+        source += &format!("#set page(width: {width})\n");
+    }
+    if settings.context.is_some() {
+        source += &format!("#import \"{}\": *\n", CONTEXT_FILE);
+    }
+    source + src
+}
+
+/// Whether a `width=` value is a Typst length like `600pt` or `12.5cm`, or `auto`
+fn is_length(value: &str) -> bool {
+    let number = ["pt", "mm", "cm", "in", "em"]
+        .iter()
+        .find_map(|unit| value.strip_suffix(unit));
+    value == "auto" || number.is_some_and(|number| number.parse::<f64>().is_ok_and(f64::is_finite))
+}
+
+/// Writes the compiled source of a block to `<src_dir>/<filename>.typ`
+fn write_source(
+    source: &str,
+    dir: &Path,
+    filename: &str,
+    settings: &PreprocessSettings,
+) -> Result<PathBuf> {
+    let mut dir = dir.to_path_buf();
+    dir.push(&settings.src_dir);
+    fs::create_dir_all(&dir).map_err(|err| write_error(&dir, err))?;
+
+    if let Some(context) = &settings.context {
+        let context_path = dir.join(CONTEXT_FILE);
+        if fs::read_to_string(&context_path).ok().as_ref() != Some(context) {
+            write_atomic(&context_path, context).map_err(|err| write_error(&context_path, err))?;
+        }
+    }
+
+    dir.push(filename.to_owned() + ".typ");
+
+    let mut file = String::new();
+    // Comments don't change the output, so the header isn't part of the hash
+    if let Some(header) = &settings.options.source_header {
+        for line in header.lines() {
+            file += &format!("// {}\n", line);
+        }
+    }
+    file += source;
+    // Another build may be compiling the same file
+    write_atomic(&dir, file).map_err(|err| write_error(&dir, err))?;
+
+    Ok(dir)
+}
+
+fn write_error(path: &Path, err: std::io::Error) -> Error {
+    anyhow!("Can't write {}: {}", path.display(), err)
+}
+
+/// Creates `typst <subcommand> <source>` with the arguments shared by all invocations
+fn typst_command(
+    subcommand: &str,
+    source: &Path,
+    root: &Path,
+    options: &BlockOptions,
+    settings: &PreprocessSettings,
+) -> Command {
+    let mut res = Command::new(&settings.typst_path);
+    res.kill_on_drop(true)
+        .arg(subcommand)
+        .arg(source)
+        .arg("--root")
+        .arg(root);
+
+    for path in &settings.font_paths {
+        res.arg("--font-path").arg(path);
+    }
+
+    if let Some(package_path) = &settings.package_path {
+        res.arg("--package-path").arg(package_path);
+    }
+
+    if let Some(package_cache_path) = &settings.package_cache_path {
+        res.arg("--package-cache-path").arg(package_cache_path);
+    }
+
+    if let Some(seed) = &options.seed {
+        res.arg("--input").arg(format!("seed={seed}"));
+    }
+
+    res.args(&settings.typst_args);
+
+    res
+}
+
+/// Typst output of a block, kept for `collapse_errors`
+struct CollectedError {
+    chapter: String,
+    /// The output without the name of the block's source, which is the same for all blocks
+    key: String,
+    /// The output as Typst wrote it, without the lines in the chapter from `BlockLines`
+    plain: String,
+    stderr: String,
+}
+
+/// What the blocks of a build leave for after the whole book. Every build has its own, as
+/// `preprocess` runs again in the same process for `watch`, and for users of the library.
+#[derive(Default)]
+struct BuildState {
+    /// Images and query results started in this build. An identical block, like in
+    /// another chapter of the same directory, is compiled once and uses the same files.
+    compiled_outputs: Mutex<BTreeSet<PathBuf>>,
+    /// Hashes of all blocks, for `prune_cache`
+    used_hashes: Mutex<BTreeSet<String>>,
+    /// Sources of running compilations, with their count, for `keep_typ_source`
+    sources_in_use: Mutex<BTreeMap<PathBuf, usize>>,
+    collected_errors: Mutex<Vec<CollectedError>>,
+    /// Chapters of the blocks that failed to compile, once per block, for `fail_on_error`
+    failed_blocks: Mutex<Vec<String>>,
+    /// Hashes of the blocks that weren't compiled because of `render_time_limit`
+    timed_out: Mutex<Vec<String>>,
+    /// Images that are used as Typst wrote them, because changing them failed
+    not_postprocessed: Mutex<Vec<PathBuf>>,
+    /// Compilations that are running, for `min_free_memory`
+    running_compiles: AtomicUsize,
+}
+
+/// Counts a compilation in `running_compiles` while it's alive, until it's done or dropped,
+/// like by `fail_fast`
+struct RunningCompile(Arc<BuildState>);
+
+impl RunningCompile {
+    fn new(state: Arc<BuildState>) -> Self {
+        state.running_compiles.fetch_add(1, Ordering::SeqCst);
+        RunningCompile(state)
+    }
+}
+
+impl Drop for RunningCompile {
+    fn drop(&mut self) {
+        self.0.running_compiles.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl BuildState {
+    /// The result of a post-processing step, or `None` if the unprocessed image should be used.
+    /// The build goes on either way, `fail_on_postprocess_error` is checked after the whole book.
+    fn postprocessed<T>(&self, result: Result<T>, image: &Path) -> Option<T> {
+        match result {
+            Ok(value) => Some(value),
+            Err(err) => {
+                warn!(
+                    "Can't post-process {}, it is used as it is: {}",
+                    image.display(),
+                    err
+                );
+                self.not_postprocessed
+                    .lock()
+                    .unwrap()
+                    .push(image.to_path_buf());
+                None
+            }
+        }
+    }
+
+    /// Prints every distinct collected output once, with the chapters it appeared in
+    fn report_collected_errors(&self) {
+        let errors = std::mem::take(&mut *self.collected_errors.lock().unwrap());
+        let mut groups: Vec<(&CollectedError, Vec<&str>)> = vec![];
+
+        for error in &errors {
+            match groups.iter_mut().find(|(first, _)| first.key == error.key) {
+                Some((_, chapters)) => chapters.push(&error.chapter),
+                None => groups.push((error, vec![&error.chapter])),
+            }
+        }
+
+        for (error, chapters) in groups {
+            let count = chapters.len();
+            let chapters = chapters
+                .into_iter()
+                .unique()
+                .map(|c| format!("\"{c}\""))
+                .join(", ");
+            if count == 1 {
+                error!("At chapter {}:\n{}", chapters, error.stderr.trim_end())
+            } else {
+                // The blocks are in different places, so the output points at none of them
+                let stderr = error.plain.trim_end();
+                error!("In {} blocks at chapters {}:\n{}", count, chapters, stderr)
+            }
+        }
+    }
+}
+
+fn typst_spawn_error(path: &str, err: std::io::Error) -> Error {
+    anyhow!(
+        "Rendering requires the Typst CLI, but it couldn't be run as {}: {}. Install Typst or set typst_path",
+        path,
+        err
+    )
+}
+
+/// Queries always run the Typst CLI, whatever compiles the blocks
+fn query_spawn_error(path: &str, err: std::io::Error) -> Error {
+    anyhow!(
+        "Blocks with query= need the Typst CLI, also with another backend, but it couldn't be run as {}: {}. Install Typst or set typst_path",
+        path,
+        err
+    )
+}
+
+/// Runs a compilation once one of `compile_slots` is free and there's enough memory, like
+/// every block is compiled. Nothing is started past `render_time_limit`. If `can_stop`, the
+/// compilation is also stopped at the limit or after `render_timeout_secs`, by dropping it.
+/// `block` is the hash of the compiled block.
+fn in_compile_slot<F: Future>(
+    compile: impl FnOnce() -> F,
+    can_stop: bool,
+    name: String,
+    block: String,
+    settings: &PreprocessSettings,
+) -> impl Future<Output = Result<F::Output>> {
+    let min_free_memory = settings.min_free_memory;
+    let compile_slots = settings.compile_slots.clone();
+    let deadline = settings.render_deadline;
+    let render_timeout = settings.render_timeout.filter(|_| can_stop);
+    let state = settings.state.clone();
+
+    async move {
+        // Held until the compilation is done
+        let _slot = compile_slots.acquire_owned().await?;
+        // It's started only here, so that waiting for memory actually delays it
+        if let Some(min_free_memory) = min_free_memory {
+            wait_for_memory(min_free_memory, &state).await;
+        }
+
+        let past_deadline = || deadline.is_some_and(|deadline| Instant::now() >= deadline);
+        // The block's own limit starts when it's started, not while it waits for a slot
+        let limit = iter::once(deadline.filter(|_| can_stop))
+            .chain(iter::once(
+                render_timeout.map(|timeout| Instant::now() + timeout),
+            ))
+            .flatten()
+            .min();
+
+        let running = RunningCompile::new(state.clone());
+        let output = match limit {
+            // Past the deadline nothing is started, and running processes are killed on drop
+            _ if past_deadline() => None,
+            Some(limit) => tokio::time::timeout_at(limit.into(), compile()).await.ok(),
+            None => Some(compile().await),
+        };
+        drop(running);
+
+        match output {
+            Some(output) => Ok(output),
+            None if past_deadline() => {
+                state.timed_out.lock().unwrap().push(block);
+                Err(anyhow!(
+                    "Render time limit exceeded at a block in chapter \"{}\"",
+                    name
+                ))
+            }
+            None => {
+                error!(
+                    "At chapter \"{}\": compilation took longer than render_timeout_secs ({}s) and was stopped",
+                    name,
+                    render_timeout.unwrap_or_default().as_secs()
+                );
+                state.failed_blocks.lock().unwrap().push(name.clone());
+                Err(anyhow!(
+                    "Typst timed out at a block in chapter \"{}\"",
+                    name
+                ))
+            }
+        }
+    }
+}
+
+/// Runs typst, reporting anything it writes to stderr. Returns its stdout on success.
+/// `block` is the hash of the compiled block.
+fn run_typst(
+    mut res: Command,
+    name: String,
+    block: String,
+    lines: Option<BlockLines>,
+    settings: &PreprocessSettings,
+) -> impl Future<Output = Result<Vec<u8>>> {
+    let collapse_errors = settings.options.collapse_errors;
+    let typst_path = settings.typst_path.clone();
+    let state = settings.state.clone();
+    // The process is spawned only when it gets a slot
+    let output = in_compile_slot(
+        move || async move { res.output().await },
+        true,
+        name.clone(),
+        block.clone(),
+        settings,
+    );
+
+    async move {
+        let output = output
+            .await?
+            .map_err(|err| typst_spawn_error(&typst_path, err))?;
+
+        let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+        let success = output.status.success();
+        report_typst_output(
+            &state,
+            stderr,
+            success,
+            &name,
+            &block,
+            lines,
+            collapse_errors,
+        )?;
+        Ok(output.stdout)
+    }
+}
+
+/// Runs a compilation with the Typst crates on a blocking thread, reporting its output like
+/// `run_typst`. Unlike a process, it can't be stopped, so `render_timeout_secs` doesn't apply.
+#[cfg(feature = "library")]
+fn run_in_process(
+    compile: impl FnOnce() -> Result<library::Compiled> + Send + 'static,
+    name: String,
+    block: String,
+    lines: Option<BlockLines>,
+    settings: &PreprocessSettings,
+) -> impl Future<Output = Result<()>> {
+    let collapse_errors = settings.options.collapse_errors;
+    let state = settings.state.clone();
+    let compiled = in_compile_slot(
+        move || tokio::task::spawn_blocking(compile),
+        false,
+        name.clone(),
+        block.clone(),
+        settings,
+    );
+
+    async move {
+        let compiled = compiled.await???;
+        report_typst_output(
+            &state,
+            compiled.diagnostics,
+            compiled.success,
+            &name,
+            &block,
+            lines,
+            collapse_errors,
+        )
+    }
+}
+
+/// Reports what Typst wrote besides the images, which is also written on success for warnings.
+/// Fails if the compilation did.
+fn report_typst_output(
+    state: &BuildState,
+    plain: String,
+    success: bool,
+    name: &str,
+    block: &str,
+    lines: Option<BlockLines>,
+    collapse_errors: bool,
+) -> Result<()> {
+    let stderr = match lines {
+        Some(lines) => lines.annotate(&plain, block),
+        None => plain.clone(),
+    };
+    if !stderr.is_empty() && collapse_errors {
+        state.collected_errors.lock().unwrap().push(CollectedError {
+            chapter: name.to_owned(),
+            key: plain.replace(block, ""),
+            plain,
+            stderr,
+        });
+    } else if !stderr.is_empty() {
+        let level = if success { Level::Warn } else { Level::Error };
+        log!(level, "At chapter \"{}\":\n{}", name, stderr.trim_end());
+    }
+
+    if success {
+        Ok(())
+    } else {
+        state.failed_blocks.lock().unwrap().push(name.to_owned());
+        Err(anyhow!(
+            "Typst failed to compile a block in chapter \"{}\"",
+            name
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Held while reading the settings, as they depend on `MDBOOK_TYPST_RENDER`
+    static ENV: Mutex<()> = Mutex::new(());
+
+    /// Settings of a book at `root`, with options as in `book.toml`
+    fn settings_in(root: &Path, options: &str) -> Result<PreprocessSettings> {
+        let table: options::Table = toml::from_str(options).unwrap();
+        let options = Options::from_table(Some(&table))?;
+        let _env = ENV.lock().unwrap_or_else(|err| err.into_inner());
+        PreprocessSettings::new(options, root, &Config::default(), "html")
+    }
+
+    /// Settings of a book in the current directory
+    fn settings(options: &str) -> PreprocessSettings {
+        settings_in(Path::new("."), options).unwrap()
+    }
+
+    /// The grammar and the code of `split_inline_lang`
+    fn inline_lang<'a>(code: &'a str, settings: &PreprocessSettings) -> Option<(&'a str, String)> {
+        split_inline_lang(code, settings).map(|(syntax, code)| (code, syntax.name.clone()))
+    }
+
+    #[test]
+    fn inline_prefix_of_typst() {
+        let settings = settings("");
+        let typst = typst_syntax().name.clone();
+        assert_eq!(
+            inline_lang("typ: #let x = 1", &settings),
+            Some(("#let x = 1", typst.clone()))
+        );
+        assert_eq!(
+            inline_lang("typc: 1 + 2", &settings),
+            Some(("1 + 2", typst))
+        );
+        assert_eq!(inline_lang("#let x = 1", &settings), None);
+    }
+
+    #[test]
+    fn inline_prefix_of_other_grammars_is_kept() {
+        let settings = settings("");
+        for code in [
+            "sh: command not found",
+            "make: *** No rule to make target",
+            "C: drive",
+            "rust: let x = 1;",
+        ] {
+            assert_eq!(inline_lang(code, &settings), None, "{code}");
+        }
+    }
+
+    #[test]
+    fn inline_prefix_of_inline_languages() {
+        let settings = settings(r#"inline_languages = ["rust"]"#);
+        assert_eq!(
+            inline_lang("rust: let x = 1;", &settings),
+            Some(("let x = 1;", "Rust".to_owned()))
+        );
+        assert_eq!(inline_lang("sh: command not found", &settings), None);
+    }
+
+    #[test]
+    fn inline_languages_need_a_grammar() {
+        let settings = settings_in(Path::new("."), r#"inline_languages = ["no-such-language"]"#);
+        assert!(settings.is_err());
+    }
+
+    #[test]
+    fn error_lines_skip_trimmed_lines_and_the_caption() {
+        let settings = settings("trim_block = true");
+        // The fence is at line 10 of the chapter, the undefined variable at line 15
+        let text = "\n  \n// caption: Broken\n#let a = 1\n#undefined\n".to_owned();
+        let (_, src, caption, skipped_lines) = block_source(text, &settings);
+        assert_eq!(caption.as_deref(), Some("Broken"));
+        assert_eq!(skipped_lines, 3);
+
+        let options = BlockOptions {
+            preamble: Some(&settings.preamble),
+            ..Default::default()
+        };
+        let source = compiled_source(&src, &options, &settings);
+        let lines = BlockLines::new(&source, &src, 11 + skipped_lines, &settings);
+        let line = source.lines().position(|l| l == "#undefined").unwrap() + 1;
+        let stderr =
+            format!("error: unknown variable: undefined\n  ┌─ typst-src/abc.typ:{line}:2\n");
+        assert!(lines
+            .annotate(&stderr, "abc")
+            .contains("(line 2 of the block, around line 15 of the chapter)"));
+    }
+
+    /// The new content of a chapter with the given Markdown, without rendering
+    fn processed(content: &str, settings: &PreprocessSettings) -> String {
+        let mut book = Book::new();
+        book.push_item(Chapter::new("Test", content.to_owned(), "test.md", vec![]));
+        process_chapters(&book, settings, Path::new("."), &mut vec![])
+            .unwrap()
+            .remove(0)
+    }
+
+    #[test]
+    fn verbatim_blocks_are_kept_as_written() {
+        let settings = settings("");
+        for block in [
+            "```typ,verbatim\n#let x = 1\n\n= Heading\n```",
+            "~~~typ-verbatim\n```typ\n#x\n```\n~~~",
+            "````typ verbatim\n````",
+        ] {
+            let content = format!("Before\n\n{block}\n\nAfter\n");
+            let processed = processed(&content, &settings);
+            assert!(processed.contains(&format!("\n{block}\n")), "{processed}");
+        }
+    }
+
+    #[test]
+    fn verbatim_blocks_in_lists_and_quotes() {
+        let settings = settings("");
+        let list = processed("- Item\n\n  ```typ,verbatim\n  #x\n  ```\n", &settings);
+        assert!(list.contains("  ```typ,verbatim\n  #x\n  ```"), "{list}");
+        let quote = processed("> ```typ,verbatim\n> #x\n> ```\n", &settings);
+        // The serializer writes its own quote markers
+        assert!(
+            quote.contains("> ```typ,verbatim\n > #x\n > ```"),
+            "{quote}"
+        );
+    }
+
+    /// A directory of its own for a test, removed first if a previous run left it
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("mdbook-typst-highlight-{name}"));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn color_profile_is_embedded_into_png() {
+        let path = test_dir("color-profile").join("image.png");
+        image::RgbaImage::new(2, 1).save(&path).unwrap();
+        let profile = b"profile".to_vec();
+
+        embed_color_profile(&path, &profile).unwrap();
+
+        let png = fs::read(&path).unwrap();
+        let mut decoder = image::codecs::png::PngDecoder::new(std::io::Cursor::new(png)).unwrap();
+        assert_eq!(
+            image::ImageDecoder::icc_profile(&mut decoder).unwrap(),
+            Some(profile)
+        );
+    }
+
+    #[test]
+    fn color_profile_is_part_of_the_png_hash() {
+        let mut png = settings(r#"format = "png""#);
+        let plain = format_hash(&png);
+        png.color_profile = Some(b"one".as_slice().into());
+        let one = format_hash(&png);
+        png.color_profile = Some(b"two".as_slice().into());
+        assert_ne!(plain, one);
+        assert_ne!(one, format_hash(&png));
+    }
+
+    #[test]
+    fn color_profile_does_nothing_for_svg() {
+        let dir = test_dir("color-profile-svg");
+        let mut profile = vec![0; 128];
+        profile[36..40].copy_from_slice(b"acsp");
+        fs::write(dir.join("profile.icc"), profile).unwrap();
+
+        let settings = settings_in(&dir, r#"color_profile = "profile.icc""#).unwrap();
+        assert!(settings.color_profile.is_none());
+        assert_eq!(format_hash(&settings), "svg");
+    }
+
+    /// The hash of a block's text as it's read from the chapter
+    fn text_hash(parts: &[&str], settings: &PreprocessSettings) -> String {
+        let mut text = String::new();
+        for part in parts {
+            push_block_text(&mut text, part, settings);
+        }
+        let (_, src, _, _) = block_source(text, settings);
+        let options = BlockOptions {
+            preamble: Some(&settings.preamble),
+            ..Default::default()
+        };
+        block_hash(&compiled_source(&src, &options, settings), "", settings)
+    }
+
+    #[test]
+    fn crlf_sources_hash_like_lf() {
+        let settings = settings("");
+        assert_eq!(
+            text_hash(&["#let x = 1\r\n", "= Heading\r\n"], &settings),
+            text_hash(&["#let x = 1\n", "= Heading\n"], &settings)
+        );
+
+        let settings = self::settings("keep_line_endings = true");
+        assert_ne!(
+            text_hash(&["#let x = 1\r\n", "= Heading\r\n"], &settings),
+            text_hash(&["#let x = 1\n", "= Heading\n"], &settings)
+        );
+    }
+
+    #[test]
+    fn padded_blocks_are_trimmed() {
+        let settings = settings("trim_block = true");
+        let (shown, src, _, _) =
+            block_source("\n \n#let x = 1\n\n#x\n\t\n\n".to_owned(), &settings);
+        assert_eq!(shown, "#let x = 1\n\n#x\n");
+        assert_eq!(src, "#let x = 1\n\n#x\n");
+        // Padding doesn't render the block again
+        assert_eq!(
+            text_hash(&["\n\n#let x = 1\n", "\n#x\n\n"], &settings),
+            text_hash(&["#let x = 1\n\n#x\n"], &settings)
+        );
+
+        let settings = self::settings("");
+        let (shown, _, _, _) = block_source("\n#x\n\n".to_owned(), &settings);
+        assert_eq!(shown, "\n#x\n\n");
+    }
+
+    #[test]
+    fn theme_file_colors_are_used() {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let settings = settings_in(root, r#"theme_file = "tests/fixtures/minimal.tmTheme""#);
+        let html = highlight_themed("#let x = 1", typst_syntax(), false, &settings.unwrap());
+        assert!(html.contains("color:#123456;"), "{html}");
+        assert!(!html.contains("var(--fg)"), "{html}");
+
+        let missing = settings_in(root, r#"theme_file = "tests/fixtures/missing.tmTheme""#);
+        assert!(missing.is_err());
+    }
+
+    #[test]
+    fn light_and_dark_themes_are_both_emitted() {
+        let settings = settings(
+            r#"
+            theme_light = "InspiredGitHub"
+            theme_dark = "base16-ocean.dark"
+            "#,
+        );
+        let content = processed("```typ\n#let x = 1\n```\n", &settings);
+        for theme in ["InspiredGitHub", "base16-ocean.dark"] {
+            let theme = book_theme(theme).unwrap();
+            let html = highlight(
+                "#let x = 1".into(),
+                typst_syntax(),
+                &theme,
+                false,
+                None,
+                &settings.color_vars,
+                "",
+            );
+            assert!(content.contains(&html), "{content}");
+        }
+        assert!(content.contains(r#"<div class="typst-hl-light">"#));
+        assert!(content.contains(r#"<div class="typst-hl-dark">"#));
+        assert!(content.contains(".navy .typst-hl-dark"));
+
+        // A single one is just the theme
+        let settings = self::settings(r#"theme_dark = "base16-ocean.dark""#);
+        let content = processed("```typ\n#let x = 1\n```\n", &settings);
+        assert!(!content.contains("typst-hl-dark"), "{content}");
+    }
+
+    #[test]
+    fn three_lines_are_numbered_1_to_3() {
+        let settings = settings("line_numbers = true");
+        let content = processed("```typ\n#let x = 1\n\n#x\n```\n", &settings);
+        assert!(
+            content.contains(
+                r#"<code class="hljs">1
+2
+3</code>"#
+            ),
+            "{content}"
+        );
+        assert!(content.contains(r#"aria-hidden="true""#));
+
+        // Not for inline code
+        let content = processed("Some `#let x = 1` code\n", &settings);
+        assert!(
+            !content.contains(r#"class="typst-line-numbers""#),
+            "{content}"
+        );
+    }
+
+    #[test]
+    fn hidden_setup_lines_are_only_compiled() {
+        let settings = settings("hide_lines = true");
+        let text = "# #set text(red)\n#\n  # #let x = 1\n## Heading\n#x\n".to_owned();
+        let (shown, src, _, _) = block_source(text, &settings);
+        assert_eq!(shown, "# Heading\n#x\n");
+        assert_eq!(src, "#set text(red)\n\n  #let x = 1\n# Heading\n#x\n");
+
+        // Without the option, the lines are code like any other
+        let settings = self::settings("");
+        let (shown, src, _, _) = block_source("# #set text(red)\n#x\n".to_owned(), &settings);
+        assert_eq!(shown, "# #set text(red)\n#x\n");
+        assert_eq!(src, shown);
+    }
+
+    #[test]
+    fn default_foreground_is_the_text_color() {
+        let settings = settings("");
+        let html = highlight_themed("#let x = 1", typst_syntax(), true, &settings);
+        assert!(
+            html.contains(r#"<span style="color:var(--fg);"> x </span>"#),
+            "{html}"
+        );
+        assert!(!html.contains(&css_color(FOREGROUND, &[])), "{html}");
+
+        // Other colors of the theme can be mapped too, like that of keywords
+        assert!(html.contains(r#"<span style="color:#859900;">#let</span>"#));
+        let settings = self::settings("[color_var_map]\n\"#859900\" = \"--keyword\"");
+        let html = highlight_themed("#let x = 1", typst_syntax(), true, &settings);
+        assert!(html.contains(r#"<span style="color:var(--keyword);">#let</span>"#));
+    }
+
+    #[test]
+    fn supported_renderers() {
+        for renderer in ["html", "epub"] {
+            assert!(TypstHighlight.supports_renderer(renderer), "{renderer}");
+        }
+        for renderer in ["markdown", "linkcheck", "pdf", "latex", "HTML", ""] {
+            assert!(!TypstHighlight.supports_renderer(renderer), "{renderer}");
+        }
+    }
+
+    #[test]
+    fn class_prefix_in_inline_and_block_code() {
+        let content = "Some `#x` code\n\n```typ\n#x\n```\n";
+        let prefixed = processed(content, &settings(r#"class_prefix = "typst-""#));
+        assert!(
+            prefixed.contains(r#"Some <code class="typst-hljs">"#),
+            "{prefixed}"
+        );
+        assert!(
+            prefixed.contains(r#"<code class="language-typst-typ typst-hljs">"#),
+            "{prefixed}"
+        );
+
+        // The usual classes by default
+        let plain = processed(content, &settings(""));
+        assert!(plain.contains(r#"Some <code class="hljs">"#), "{plain}");
+        assert!(
+            plain.contains(r#"<code class="language-typ hljs">"#),
+            "{plain}"
+        );
+    }
+
+    #[test]
+    fn render_env_overrides_the_config() {
+        let render = |env: Option<&str>, options: &str| {
+            let table = toml::from_str(options).unwrap();
+            let options = Options::from_table(Some(&table)).unwrap();
+            let _env = ENV.lock().unwrap_or_else(|err| err.into_inner());
+            match env {
+                Some(value) => std::env::set_var("MDBOOK_TYPST_RENDER", value),
+                None => std::env::remove_var("MDBOOK_TYPST_RENDER"),
+            }
+            let settings =
+                PreprocessSettings::new(options, Path::new("."), &Config::default(), "html");
+            std::env::remove_var("MDBOOK_TYPST_RENDER");
+            settings.map(|settings| settings.render)
+        };
+
+        assert!(!render(None, "").unwrap());
+        assert!(render(None, "render = true").unwrap());
+        assert!(render(Some("true"), "").unwrap());
+        assert!(render(Some("1"), "render = false").unwrap());
+        assert!(!render(Some("false"), "render = true").unwrap());
+        assert!(!render(Some("no"), "render = true").unwrap());
+        assert!(render(Some("maybe"), "").is_err());
+    }
 }
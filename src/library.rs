@@ -0,0 +1,249 @@
+//! Compilation with the Typst crates in this process instead of the Typst CLI, enabled with
+//! the `library` feature and `backend = "library"`
+
+use std::path::PathBuf;
+#[cfg(feature = "library")]
+use std::sync::Arc;
+
+#[cfg(feature = "library")]
+use anyhow::anyhow;
+#[cfg(feature = "library")]
+use mdbook::errors::Result;
+#[cfg(feature = "library")]
+use std::path::Path;
+#[cfg(feature = "library")]
+use std::sync::OnceLock;
+#[cfg(feature = "library")]
+use typst::diag::{FileResult, Warned};
+#[cfg(feature = "library")]
+use typst::foundations::{Bytes, Datetime, Dict, Duration, Value};
+#[cfg(feature = "library")]
+use typst::syntax::{FileId, RootedPath, Source, VirtualPath, VirtualRoot};
+#[cfg(feature = "library")]
+use typst::text::{Font, FontBook};
+#[cfg(feature = "library")]
+use typst::utils::LazyHash;
+#[cfg(feature = "library")]
+use typst::{LibraryExt, World};
+#[cfg(feature = "library")]
+use typst_kit::datetime::Time;
+#[cfg(feature = "library")]
+use typst_kit::diagnostics::{termcolor::NoColor, DiagnosticFormat, DiagnosticWorld};
+#[cfg(feature = "library")]
+use typst_kit::files::{FileStore, FsRoot, SystemFiles};
+#[cfg(feature = "library")]
+use typst_kit::fonts::FontStore;
+#[cfg(feature = "library")]
+use typst_kit::packages::{FsPackages, SystemPackages, UniversePackages};
+#[cfg(feature = "library")]
+use typst_layout::PagedDocument;
+
+/// What the compiler uses besides the block, like the directories given to the CLI
+#[derive(Clone)]
+#[cfg_attr(not(feature = "library"), allow(dead_code))]
+pub(crate) struct Library {
+    /// Searched for fonts before the system and the embedded fonts
+    pub font_dirs: Vec<PathBuf>,
+    pub package_path: Option<PathBuf>,
+    pub package_cache_path: Option<PathBuf>,
+    /// Fonts of all blocks of a build, searched for when the first one compiles. `watch` sets
+    /// up every rebuild anew, so changed `font_paths` and fonts added to them are found.
+    #[cfg(feature = "library")]
+    pub fonts: Arc<OnceLock<FontStore>>,
+}
+
+#[cfg(feature = "library")]
+fn fonts(library: &Library) -> &FontStore {
+    library.fonts.get_or_init(|| {
+        let mut fonts = FontStore::new();
+        for dir in &library.font_dirs {
+            fonts.extend(typst_kit::fonts::scan(dir));
+        }
+        fonts.extend(typst_kit::fonts::system());
+        fonts.extend(typst_kit::fonts::embedded());
+        fonts
+    })
+}
+
+/// What a compilation wrote besides the images
+#[cfg(feature = "library")]
+pub(crate) struct Compiled {
+    pub success: bool,
+    /// Errors and warnings, formatted like the CLI does
+    pub diagnostics: String,
+}
+
+/// Compiles the block's source into `<output>-<page>.svg` images, for the selected `pages`
+/// if given, in the syntax of `typst compile --pages`
+#[cfg(feature = "library")]
+pub(crate) fn compile(
+    library: &Library,
+    source: &Path,
+    root: &Path,
+    seed: Option<&str>,
+    pages: Option<&str>,
+    output: &Path,
+) -> Result<Compiled> {
+    let Some(ranges) = pages.map_or(Some(vec![(None, None)]), page_ranges) else {
+        return Ok(Compiled {
+            success: false,
+            diagnostics: format!("error: invalid page ranges \"{}\"\n", pages.unwrap()),
+        });
+    };
+
+    let world = BlockWorld::new(library, source, root, seed)?;
+    let Warned {
+        output: document,
+        warnings,
+    } = typst::compile::<PagedDocument>(&world);
+
+    let (success, diagnostics) = match document {
+        Ok(document) => {
+            let name = output.file_name().unwrap().to_string_lossy();
+            for (i, page) in document.pages().iter().enumerate() {
+                let n = i + 1;
+                let selected = ranges.iter().any(|&(first, last)| {
+                    first.is_none_or(|first| first <= n) && last.is_none_or(|last| n <= last)
+                });
+                if selected {
+                    let svg = typst_svg::svg(page, &Default::default());
+                    std::fs::write(output.with_file_name(format!("{name}-{n}.svg")), svg)?;
+                }
+            }
+            (true, warnings.to_vec())
+        }
+        Err(errors) => (false, errors.iter().chain(&warnings).cloned().collect()),
+    };
+
+    let mut stderr = NoColor::new(vec![]);
+    typst_kit::diagnostics::emit(&mut stderr, &world, &diagnostics, DiagnosticFormat::Human)
+        .map_err(|err| anyhow!("Can't format the Typst output: {}", err))?;
+
+    // Memoized results of long gone blocks are dropped, like the CLI does between compilations
+    typst::comemo::evict(10);
+
+    Ok(Compiled {
+        success,
+        diagnostics: String::from_utf8_lossy(&stderr.into_inner()).into_owned(),
+    })
+}
+
+/// Ranges like `2`, `3-5`, `7-` or `-4`, separated by commas. The pages are counted from 1.
+#[cfg(feature = "library")]
+fn page_ranges(pages: &str) -> Option<Vec<(Option<usize>, Option<usize>)>> {
+    let page = |n: &str| -> Option<Option<usize>> {
+        match n.trim() {
+            "" => Some(None),
+            n => n.parse().ok().filter(|&n| n > 0).map(Some),
+        }
+    };
+    pages
+        .split(',')
+        .map(|range| match range.split_once('-') {
+            Some((first, last)) => Some((page(first)?, page(last)?)),
+            None => page(range)?.map(|n| (Some(n), Some(n))),
+        })
+        .collect()
+}
+
+/// The packages of blocks come from `package_path` and `package_cache_path`, or where the
+/// CLI keeps them. Nothing is downloaded, missing packages are errors.
+#[cfg(feature = "library")]
+struct NoDownloads;
+
+#[cfg(feature = "library")]
+impl typst_kit::downloader::Downloader for NoDownloads {
+    fn stream(
+        &self,
+        _key: &dyn std::any::Any,
+        url: &str,
+    ) -> std::io::Result<(Option<usize>, Box<dyn std::io::Read>)> {
+        Err(std::io::Error::other(format!(
+            "the library backend doesn't download packages, like {url}. Download them with the Typst CLI or put them into package_path"
+        )))
+    }
+}
+
+#[cfg(feature = "library")]
+struct BlockWorld<'a> {
+    library: LazyHash<typst::Library>,
+    fonts: &'a FontStore,
+    files: FileStore<SystemFiles>,
+    main: FileId,
+    time: Time,
+}
+
+#[cfg(feature = "library")]
+impl<'a> BlockWorld<'a> {
+    fn new(library: &'a Library, source: &Path, root: &Path, seed: Option<&str>) -> Result<Self> {
+        let path = VirtualPath::virtualize(root, source)
+            .map_err(|err| anyhow!("Can't compile {}: {}", source.display(), err))?;
+        let inputs: Dict = seed
+            .map(|seed| ("seed".into(), Value::Str(seed.into())))
+            .into_iter()
+            .collect();
+        let packages = SystemPackages::from_parts(
+            library
+                .package_path
+                .clone()
+                .map(FsPackages::new)
+                .or_else(FsPackages::system_data),
+            library
+                .package_cache_path
+                .clone()
+                .map(FsPackages::new)
+                .or_else(FsPackages::system_cache),
+            UniversePackages::new(NoDownloads),
+        );
+
+        Ok(BlockWorld {
+            library: LazyHash::new(typst::Library::builder().with_inputs(inputs).build()),
+            fonts: fonts(library),
+            files: FileStore::new(SystemFiles::new(FsRoot::new(root.to_path_buf()), packages)),
+            main: RootedPath::new(VirtualRoot::Project, path).intern(),
+            time: Time::system(),
+        })
+    }
+}
+
+#[cfg(feature = "library")]
+impl World for BlockWorld<'_> {
+    fn library(&self) -> &LazyHash<typst::Library> {
+        &self.library
+    }
+
+    fn book(&self) -> &LazyHash<FontBook> {
+        self.fonts.book()
+    }
+
+    fn main(&self) -> FileId {
+        self.main
+    }
+
+    fn source(&self, id: FileId) -> FileResult<Source> {
+        self.files.source(id)
+    }
+
+    fn file(&self, id: FileId) -> FileResult<Bytes> {
+        self.files.file(id)
+    }
+
+    fn font(&self, index: usize) -> Option<Font> {
+        self.fonts.font(index)
+    }
+
+    fn today(&self, offset: Option<Duration>) -> Option<Datetime> {
+        self.time.today(offset)
+    }
+}
+
+#[cfg(feature = "library")]
+impl DiagnosticWorld for BlockWorld<'_> {
+    /// Paths relative to the root, which contain the block's hash like those the CLI gives
+    fn name(&self, id: FileId) -> String {
+        match id.root() {
+            VirtualRoot::Package(package) => format!("{package}{}", id.vpath().get_with_slash()),
+            VirtualRoot::Project => id.vpath().get_without_slash().to_owned(),
+        }
+    }
+}
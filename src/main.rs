@@ -1,29 +1,79 @@
 use clap::{Arg, ArgMatches, Command};
+use itertools::Itertools;
 use mdbook::errors::Error;
 use mdbook::preprocess::{CmdPreprocessor, Preprocessor};
-use mdbook_typst_highlight::TypstHighlight;
+use mdbook_typst_highlight::{check_grammar, TypstHighlight};
 use semver::{Version, VersionReq};
-use std::io;
+use std::io::{self, Write};
+use std::path::Path;
 use std::process;
 
+/// Logs like mdbook does, so warnings and Typst errors look like the rest of the build.
+/// `RUST_LOG` filters them, as for mdbook.
+fn init_logger() {
+    let env = env_logger::Env::default().default_filter_or("info");
+    env_logger::Builder::from_env(env)
+        .format(|formatter, record| {
+            writeln!(
+                formatter,
+                "{} [{}] ({}): {}",
+                chrono::Local::now().format("%Y-%m-%d %H:%M:%S"),
+                record.level(),
+                record.target(),
+                record.args()
+            )
+        })
+        .init();
+}
+
 pub fn make_app() -> Command {
-    Command::new("typst-highlight")
+    let app = Command::new("typst-highlight")
         .about("A mdbook preprocessor that highlights Typst code")
         .subcommand(
             Command::new("supports")
                 .arg(Arg::new("renderer").required(true))
                 .about("Check whether a renderer is supported by this preprocessor"),
         )
+        .subcommand(
+            Command::new("check-grammar")
+                .arg(Arg::new("dir").required(true))
+                .about("Report places in .typ files that the bundled grammar doesn't highlight"),
+        );
+
+    #[cfg(feature = "watch")]
+    let app = app.subcommand(
+        Command::new("watch")
+            .arg(Arg::new("dir").default_value("."))
+            .about("Render changed blocks of the book into the cache, ahead of `mdbook serve`"),
+    );
+
+    app
 }
 
 fn main() {
+    init_logger();
     let matches = make_app().get_matches();
 
     // Users will want to construct their own preprocessor here
     let preprocessor = TypstHighlight;
 
+    #[cfg(feature = "watch")]
+    if let Some(sub_args) = matches.subcommand_matches("watch") {
+        let dir = sub_args.get_one::<String>("dir").expect("Has a default");
+        if let Err(e) = mdbook_typst_highlight::watch::watch(Path::new(dir)) {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+        return;
+    }
+
     if let Some(sub_args) = matches.subcommand_matches("supports") {
         handle_supports(&preprocessor, sub_args);
+    } else if let Some(sub_args) = matches.subcommand_matches("check-grammar") {
+        if let Err(e) = handle_check_grammar(sub_args) {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
     } else if let Err(e) = handle_preprocessing(&preprocessor) {
         eprintln!("{}", e);
         process::exit(1);
@@ -52,6 +102,28 @@ fn handle_preprocessing(pre: &dyn Preprocessor) -> Result<(), Error> {
     Ok(())
 }
 
+fn handle_check_grammar(sub_args: &ArgMatches) -> Result<(), Error> {
+    let dir = sub_args
+        .get_one::<String>("dir")
+        .expect("Required argument");
+    let gaps = check_grammar(Path::new(dir))?;
+
+    for gap in &gaps {
+        println!(
+            "{}:{}: unhighlighted `{}`\n    {}",
+            gap.file.display(),
+            gap.line,
+            gap.problem,
+            gap.text
+        );
+    }
+
+    let files = gaps.iter().map(|gap| &gap.file).unique().count();
+    println!("{} suspicious lines in {} files", gaps.len(), files);
+
+    Ok(())
+}
+
 fn handle_supports(pre: &dyn Preprocessor, sub_args: &ArgMatches) -> ! {
     let renderer = sub_args
         .get_one::<String>("renderer")
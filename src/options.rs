@@ -0,0 +1,162 @@
+//! The options of `[preprocessor.typst-highlight]` in `book.toml`, see the README
+
+use anyhow::anyhow;
+use mdbook::errors::Result;
+use serde::Deserialize;
+
+pub(crate) type Table = toml::map::Map<String, toml::Value>;
+
+/// Every option as it is written. Missing ones get their defaults; checks that need several
+/// options, and defaults that depend on the book, are left to the settings.
+/// Other keys, like mdbook's `command` or `renderers`, are ignored.
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub(crate) struct Options {
+    pub disable_inline: bool,
+    pub inline_require_lang: bool,
+    pub typst_default: bool,
+    pub render: bool,
+    pub warn_not_specified: bool,
+    pub assets_list: bool,
+    pub manifest: bool,
+    pub keep_line_endings: bool,
+    pub contact_sheet: bool,
+    pub fail_fast: bool,
+    pub source_link: bool,
+    pub trim_block: bool,
+    pub collapse_errors: bool,
+    pub keep_caption_comment: bool,
+    pub checksums: bool,
+    pub dedupe_in_chapter: bool,
+    pub offline: bool,
+    pub experimental_dark_svg: bool,
+    pub render_dark: bool,
+    pub dark_page_fill: Option<String>,
+    pub classed: bool,
+    pub scope_class_prefix: Option<String>,
+    pub class_prefix: Option<String>,
+    pub debug_hash: bool,
+    pub max_inline_length: Option<usize>,
+    pub context_file: Option<String>,
+    pub package_path: Option<String>,
+    pub font_paths: Option<Vec<String>>,
+    pub package_cache_path: Option<String>,
+    pub css_vars: Option<Table>,
+    pub allowed_roots: Vec<String>,
+    pub typst_root: Option<String>,
+    pub inline_cache_size: Option<usize>,
+    pub source_header: Option<String>,
+    pub math_delimiter: Option<String>,
+    pub min_free_memory: Option<u64>,
+    pub print_css: bool,
+    pub fragments_dir: Option<String>,
+    pub pages_layout: Option<String>,
+    pub layout: Option<String>,
+    pub render_inline: bool,
+    /// A string or a number, passed to Typst as it is written
+    pub seed: Option<toml::Value>,
+    pub og_image: bool,
+    pub client_math: bool,
+    pub client_math_class: Option<String>,
+    pub inline_preview: bool,
+    pub embed_max_bytes: Option<u64>,
+    pub render_time_limit: Option<u64>,
+    pub fail_on_time_limit: bool,
+    pub fail_on_error: bool,
+    pub line_numbers: bool,
+    pub hide_lines: bool,
+    pub fail_on_postprocess_error: bool,
+    pub remote_url: Option<String>,
+    pub remote_auth_env: Option<String>,
+    pub remote_timeout: Option<u64>,
+    pub backend: Option<String>,
+    pub caption_template: Option<String>,
+    pub figure_number: Option<String>,
+    pub continuous_figure_numbers: bool,
+    pub gallery: Option<String>,
+    pub theme_file: Option<String>,
+    pub theme_light: Option<String>,
+    pub theme_dark: Option<String>,
+    pub theme: Option<String>,
+    pub highlight_threads: Option<usize>,
+    pub preamble: Option<String>,
+    pub preamble_file: Option<String>,
+    pub format: Option<String>,
+    pub ppi: Option<u64>,
+    pub color_profile: Option<String>,
+    pub typst_path: Option<String>,
+    pub max_concurrency: Option<usize>,
+    pub color_var_map: Option<Table>,
+    pub inline_svg: bool,
+    pub typst_args: Vec<String>,
+    pub inputs: Option<Table>,
+    pub render_timeout_secs: Option<u64>,
+    pub img_dir: Option<String>,
+    pub src_dir: Option<String>,
+    #[serde(default = "default_true")]
+    pub keep_typ_source: bool,
+    pub prune_cache: bool,
+    pub lazy_images: bool,
+    pub transparent_background: bool,
+    pub image_max_width: Option<String>,
+    pub image_align: Option<String>,
+    pub languages: Option<Vec<String>>,
+    pub inline_languages: Vec<String>,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+impl Options {
+    /// Reads the preprocessor's table. Errors name the key of a wrong type.
+    pub(crate) fn from_table(preprocessor: Option<&Table>) -> Result<Options> {
+        // Also without the table, for the defaults of `serde(default = ...)`
+        let preprocessor = preprocessor.cloned().unwrap_or_default();
+        toml::Value::Table(preprocessor)
+            .try_into()
+            .map_err(|err| anyhow!("Incorrect preprocessor config in book.toml: {}", err))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(toml: &str) -> Result<Options> {
+        Options::from_table(Some(&toml::from_str(toml).unwrap()))
+    }
+
+    #[test]
+    fn valid_config() {
+        let options = options(
+            r#"
+            render = true
+            ppi = 300
+            typst_args = ["--input", "a=b"]
+            theme = "InspiredGitHub"
+            command = "mdbook-typst-highlight"
+            "#,
+        )
+        .unwrap();
+        assert!(options.render);
+        assert_eq!(options.ppi, Some(300));
+        assert_eq!(options.typst_args, ["--input", "a=b"]);
+        assert_eq!(options.theme.as_deref(), Some("InspiredGitHub"));
+        // Defaults of what isn't set
+        assert!(!options.trim_block);
+        assert_eq!(options.max_concurrency, None);
+
+        assert!(!Options::from_table(None).unwrap().render);
+    }
+
+    #[test]
+    fn type_mismatch_names_the_key() {
+        let err = options(r#"render = "yes""#).err().unwrap().to_string();
+        assert!(err.contains("render"), "{err}");
+        let err = options("ppi = [300]").err().unwrap().to_string();
+        assert!(err.contains("ppi"), "{err}");
+        let err = options("max_concurrency = -1").err().unwrap().to_string();
+        assert!(err.contains("max_concurrency"), "{err}");
+    }
+}
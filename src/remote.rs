@@ -0,0 +1,80 @@
+//! Compilation by an HTTP service instead of the local Typst, enabled with the `remote` feature
+
+use std::time::Duration;
+
+#[cfg(feature = "remote")]
+use anyhow::anyhow;
+#[cfg(feature = "remote")]
+use mdbook::errors::Result;
+#[cfg(feature = "remote")]
+use std::path::PathBuf;
+#[cfg(feature = "remote")]
+use std::sync::Arc;
+
+#[cfg(feature = "remote")]
+use crate::BuildState;
+
+/// Where blocks are sent, from `remote_url`
+#[derive(Clone)]
+#[cfg_attr(not(feature = "remote"), allow(dead_code))]
+pub(crate) struct Remote {
+    pub url: String,
+    /// Value of the `Authorization` header
+    pub auth: Option<String>,
+    pub timeout: Duration,
+}
+
+/// Sends the complete source to the service, which answers with the SVG of the first page
+#[cfg(feature = "remote")]
+pub(crate) async fn compile(
+    remote: Remote,
+    source: PathBuf,
+    output: PathBuf,
+    name: String,
+    state: Arc<BuildState>,
+) -> Result<()> {
+    let src = std::fs::read_to_string(source)?;
+    let response = tokio::task::spawn_blocking(move || request(&remote, &src)).await?;
+
+    match response {
+        Ok(svg) => Ok(std::fs::write(output, svg)?),
+        Err(err) => {
+            log::error!("At chapter \"{}\":\n{}", name, err);
+            state.failed_blocks.lock().unwrap().push(name.clone());
+            Err(anyhow!(
+                "The service failed to compile a block in chapter \"{}\"",
+                name
+            ))
+        }
+    }
+}
+
+#[cfg(feature = "remote")]
+fn request(remote: &Remote, src: &str) -> Result<Vec<u8>> {
+    use std::io::Read;
+
+    let agent = ureq::AgentBuilder::new().timeout(remote.timeout).build();
+    let mut request = agent
+        .post(&remote.url)
+        .set("Content-Type", "text/plain; charset=utf-8");
+    if let Some(auth) = &remote.auth {
+        request = request.set("Authorization", auth);
+    }
+
+    let response = match request.send_string(src) {
+        Ok(response) => response,
+        // The body of an error response explains it, like the stderr of Typst
+        Err(ureq::Error::Status(code, response)) => {
+            return Err(anyhow!(
+                "{} {}",
+                code,
+                response.into_string().unwrap_or_default()
+            ))
+        }
+        Err(err) => return Err(err.into()),
+    };
+
+    let mut svg = vec![];
+    response.into_reader().read_to_end(&mut svg)?;
+    Ok(svg)
+}
@@ -0,0 +1,71 @@
+//! Rendering of changed blocks into the cache while authoring, before `mdbook serve` rebuilds
+
+use std::path::Path;
+use std::sync::mpsc;
+use std::time::Duration;
+
+use mdbook::book::load_book;
+use mdbook::errors::Result;
+use mdbook::preprocess::Preprocessor;
+use mdbook::Config;
+use notify::{RecursiveMode, Watcher};
+
+use crate::options::Options;
+use crate::{cache_dirs, TypstHighlight};
+
+/// Files written by the preprocessor itself, which must not start another prebuild.
+/// `dirs` are the names of its image and source directories.
+fn is_generated(path: &Path, dirs: &[String]) -> bool {
+    path.components()
+        .any(|c| dirs.iter().any(|dir| c.as_os_str() == dir.as_str()))
+        || matches!(
+            path.file_name().and_then(|name| name.to_str()),
+            Some("typst-assets.txt" | "typst-manifest.json" | "typst-checksums.txt")
+        )
+}
+
+fn prebuild(root: &Path) -> Result<()> {
+    // Read on every change, as mdbook does
+    let config = Config::from_disk(root.join("book.toml"))?;
+    let book = load_book(root.join(&config.book.src), &config.build)?;
+    // The cache is the same for every renderer
+    TypstHighlight.preprocess(root, &config, "html", book)?;
+    Ok(())
+}
+
+/// Renders all blocks of the book at `root`, and renders them again on every change of its
+/// sources. Only the cache is written, so `mdbook serve` finds the images ready.
+pub fn watch(root: &Path) -> Result<()> {
+    let config = Config::from_disk(root.join("book.toml"))?;
+    let src = root.join(&config.book.src);
+    let options = Options::from_table(config.get_preprocessor(TypstHighlight.name()))?;
+    let (img_dir, src_dir) = cache_dirs(&options)?;
+    let dirs = [img_dir, src_dir];
+
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(tx)?;
+    watcher.watch(&src, RecursiveMode::Recursive)?;
+
+    if let Err(err) = prebuild(root) {
+        log::error!("{}", err);
+    }
+    log::info!("Watching {} for changes", src.display());
+
+    for event in &rx {
+        let changed = event.is_ok_and(|event| {
+            !event.kind.is_access() && event.paths.iter().any(|path| !is_generated(path, &dirs))
+        });
+        if !changed {
+            continue;
+        }
+
+        // Editors save in several steps, so wait for them to finish
+        while rx.recv_timeout(Duration::from_millis(200)).is_ok() {}
+
+        if let Err(err) = prebuild(root) {
+            log::error!("{}", err);
+        }
+    }
+
+    Ok(())
+}
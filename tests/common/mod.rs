@@ -0,0 +1,160 @@
+//! Books rendered with a stub instead of Typst. The stub logs its arguments, one invocation
+//! per line, and writes an empty image for every page, of which `#pagebreak()` adds one.
+//! Sources containing `STUB-ERROR` fail to compile, with `STUB-SLEEP` compiling takes a while.
+#![allow(dead_code)]
+
+use mdbook::book::{Book, BookItem, Chapter};
+use mdbook::errors::Result;
+use mdbook::preprocess::{CmdPreprocessor, Preprocessor};
+use mdbook::Config;
+use mdbook_typst_highlight::TypstHighlight;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+const STUB: &str = r#"#!/bin/sh
+log="$(dirname "$0")/typst.log"
+echo "$*" >> "$log"
+case "$1" in
+    query) echo '[]'; exit ;;
+    c) ;;
+    *) exit ;;
+esac
+if grep -q STUB-ERROR "$2"; then
+    echo "error: stub error" >&2
+    exit 1
+fi
+if grep -q STUB-SLEEP "$2"; then
+    sleep 0.3
+fi
+pages=$(($(grep -o '#pagebreak()' "$2" | wc -l) + 1))
+for arg; do
+    case "$arg" in
+        *'{n}'*)
+            for n in $(seq "$pages"); do
+                echo '<svg xmlns="http://www.w3.org/2000/svg" width="10pt" height="10pt" viewBox="0 0 15 30"></svg>' \
+                    > "${arg%%'{n}'*}$n${arg#*'{n}'}"
+            done ;;
+    esac
+done
+echo "done $2" >> "$log"
+"#;
+
+/// A book in a directory of its own, which renders its blocks with the stub
+pub struct TestBook {
+    pub root: PathBuf,
+    /// The stub by default
+    pub typst_path: PathBuf,
+    options: String,
+}
+
+impl TestBook {
+    /// `options` are added to the preprocessor's table in `book.toml`
+    pub fn new(name: &str, options: &str) -> TestBook {
+        let root = Path::new(env!("CARGO_TARGET_TMPDIR")).join(name);
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("src")).unwrap();
+
+        let stub = root.join("typst");
+        fs::write(&stub, STUB).unwrap();
+        fs::set_permissions(&stub, fs::Permissions::from_mode(0o755)).unwrap();
+
+        TestBook {
+            typst_path: stub,
+            root,
+            options: options.to_owned(),
+        }
+    }
+
+    /// The book source directory
+    pub fn src(&self) -> PathBuf {
+        self.root.join("src")
+    }
+
+    /// Writes a file of the book, relative to the book root
+    pub fn write(&self, path: &str, contents: &str) {
+        let path = self.root.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+
+    /// Runs the preprocessor on chapters given by their path and content, like mdbook does,
+    /// and returns their new contents
+    pub fn run(&self, chapters: &[(&str, &str)]) -> Result<Vec<String>> {
+        let config = format!(
+            "[preprocessor.typst-highlight]\nrender = true\ntypst_path = \"{}\"\n{}",
+            self.typst_path.display(),
+            self.options
+        );
+        let config = Config::from_str(&config)?;
+
+        let mut book = Book::new();
+        for (path, content) in chapters {
+            book.push_item(Chapter::new("Test", content.to_string(), path, vec![]));
+        }
+
+        let input = serde_json::json!([
+            {
+                "root": self.root,
+                "config": config,
+                "renderer": "html",
+                "mdbook_version": mdbook::MDBOOK_VERSION,
+            },
+            book,
+        ]);
+        let (ctx, book) = CmdPreprocessor::parse_input(input.to_string().as_bytes())?;
+        let book = TypstHighlight.run(&ctx, book)?;
+
+        Ok(book
+            .iter()
+            .filter_map(|item| match item {
+                BookItem::Chapter(chapter) => Some(chapter.content.clone()),
+                _ => None,
+            })
+            .collect())
+    }
+
+    /// Contents of the `.typ` files in a directory, relative to the book root
+    pub fn sources(&self, dir: &str) -> Vec<String> {
+        fs::read_dir(self.root.join(dir))
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|e| e == "typ"))
+            .map(|path| fs::read_to_string(path).unwrap())
+            .collect()
+    }
+
+    /// Arguments of every call of the stub, and `done <source>` lines when compilations end
+    pub fn log(&self) -> Vec<String> {
+        fs::read_to_string(self.root.join("typst.log"))
+            .unwrap_or_default()
+            .lines()
+            .map(str::to_owned)
+            .collect()
+    }
+
+    /// Arguments of the `typst c` calls, without the subcommand
+    pub fn compiles(&self) -> Vec<Vec<String>> {
+        self.log()
+            .iter()
+            .filter_map(|line| line.strip_prefix("c "))
+            .map(|args| args.split(' ').map(str::to_owned).collect())
+            .collect()
+    }
+}
+
+/// The value following `flag` in arguments
+pub fn arg<'a>(args: &'a [String], flag: &str) -> Option<&'a str> {
+    let i = args.iter().position(|a| a == flag)?;
+    args.get(i + 1).map(String::as_str)
+}
+
+/// Names of the images in a chapter, in their order
+pub fn images(content: &str) -> Vec<&str> {
+    content
+        .split("src=\"typst-img/")
+        .skip(1)
+        .map(|rest| &rest[..rest.find('"').unwrap()])
+        .collect()
+}
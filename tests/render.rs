@@ -0,0 +1,479 @@
+//! Rendering of blocks, with a stub standing in for Typst
+#![cfg(unix)]
+
+mod common;
+
+use common::{arg, images, TestBook};
+use std::path::Path;
+
+#[test]
+fn root_is_the_book_source_at_every_depth() {
+    let book = TestBook::new("root-depths", "");
+    let block = |n: usize| format!("```typ\n#{n}\n```\n");
+    book.run(&[
+        ("top.md", &block(0)),
+        ("one/chapter.md", &block(1)),
+        ("one/two/three/chapter.md", &block(3)),
+    ])
+    .unwrap();
+
+    let compiles = book.compiles();
+    assert_eq!(compiles.len(), 3);
+    let src = book.src().display().to_string();
+    for compile in &compiles {
+        assert_eq!(arg(compile, "--root"), Some(src.as_str()));
+    }
+    // Each source is next to its chapter, so relative paths are resolved from there
+    for dir in ["", "one/", "one/two/three/"] {
+        let dir = format!("{src}/{dir}typst-src/");
+        assert!(
+            compiles.iter().any(|c| c[0].starts_with(&dir)),
+            "{dir} {compiles:?}"
+        );
+    }
+}
+
+#[test]
+fn preambles_render_to_distinct_images() {
+    let book = TestBook::new("preamble-hash", "");
+    book.write("src/b.preamble.typ", "#set page(width: 300pt)");
+    let block = "```typ\n#x\n```\n";
+    let contents = book.run(&[("a.md", block), ("b.md", block)]).unwrap();
+
+    let (a, b) = (images(&contents[0]), images(&contents[1]));
+    assert_eq!((a.len(), b.len()), (1, 1));
+    assert_ne!(a, b);
+    assert_eq!(book.compiles().len(), 2);
+}
+
+#[test]
+fn block_preamble_is_written_into_its_source_only() {
+    let book = TestBook::new("block-preamble", "keep_typ_source = true");
+    book.write("src/part/extra.typ", "#let extra = [Extra]");
+    book.run(&[(
+        "part/chapter.md",
+        "```typ,preamble=extra.typ\n#extra\n```\n\n```typ\n#other\n```\n",
+    )])
+    .unwrap();
+
+    let sources = book.sources("src/part/typst-src");
+    assert_eq!(sources.len(), 2);
+    let with = sources.iter().find(|s| s.contains("#extra\n")).unwrap();
+    let without = sources.iter().find(|s| s.contains("#other")).unwrap();
+    assert!(with.contains("#let extra = [Extra]\n"), "{with}");
+    assert!(!without.contains("#let extra"), "{without}");
+}
+
+#[test]
+fn typst_path_is_run() {
+    let book = TestBook::new("typst-path", "");
+    let contents = book.run(&[("chapter.md", "```typ\n#x\n```\n")]).unwrap();
+
+    // Checked once, then compiled
+    assert_eq!(book.log()[0], "--version");
+    assert_eq!(book.compiles().len(), 1);
+    let image = images(&contents[0])[0];
+    assert!(book.src().join("typst-img").join(image).is_file());
+}
+
+/// Chapters with blocks that take a while each
+fn slow_blocks(n: usize) -> String {
+    (0..n)
+        .map(|i| format!("```typ\n// STUB-SLEEP\n#{i}\n```\n\n"))
+        .collect()
+}
+
+/// The most compilations that ran at once
+fn most_at_once(book: &TestBook) -> usize {
+    let mut running = 0;
+    let mut most = 0;
+    for line in book.log() {
+        if line.starts_with("c ") {
+            running += 1;
+            most = most.max(running);
+        } else if line.starts_with("done ") {
+            running -= 1;
+        }
+    }
+    most
+}
+
+#[test]
+fn max_concurrency_serializes_compilations() {
+    let book = TestBook::new("max-concurrency-1", "max_concurrency = 1");
+    book.run(&[("chapter.md", &slow_blocks(3))]).unwrap();
+    assert_eq!(book.compiles().len(), 3);
+    assert_eq!(most_at_once(&book), 1, "{:#?}", book.log());
+
+    let book = TestBook::new("max-concurrency-3", "max_concurrency = 3");
+    book.run(&[("chapter.md", &slow_blocks(3))]).unwrap();
+    assert_eq!(most_at_once(&book), 3, "{:#?}", book.log());
+}
+
+#[test]
+fn typst_args_and_inputs_are_passed() {
+    let chapter = [("chapter.md", "```typ\n#x\n```\n")];
+    let book = TestBook::new(
+        "typst-args",
+        r#"
+        typst_args = ["--input", "release=true"]
+        inputs = { mode = "print" }
+        "#,
+    );
+    let release = book.run(&chapter).unwrap();
+    let compiles = book.compiles();
+    assert_eq!(compiles.len(), 1);
+    let args = compiles[0].join(" ");
+    assert!(
+        args.contains(" --input release=true --input mode=print "),
+        "{args}"
+    );
+
+    // Other arguments render the block again
+    let book = TestBook::new(
+        "typst-args-changed",
+        r#"typst_args = ["--input", "release=false"]"#,
+    );
+    let other = book.run(&chapter).unwrap();
+    assert_ne!(images(&release[0]), images(&other[0]));
+}
+
+#[test]
+fn missing_typst_is_reported_once() {
+    let mut book = TestBook::new("missing-typst", "");
+    book.typst_path = book.root.join("no-typst");
+    let err = book
+        .run(&[("chapter.md", "```typ\n#x\n```\n\n```typ\n#y\n```\n")])
+        .unwrap_err()
+        .to_string();
+    assert!(err.starts_with("Rendering requires the Typst CLI"), "{err}");
+    assert!(
+        err.contains(&book.typst_path.display().to_string()),
+        "{err}"
+    );
+    assert!(err.contains("set typst_path"), "{err}");
+}
+
+#[test]
+fn width_is_written_into_the_source() {
+    let book = TestBook::new("block-width", "keep_typ_source = true");
+    book.run(&[(
+        "chapter.md",
+        "```typ,width=900pt\n#wide\n```\n\n```typ\n#narrow\n```\n",
+    )])
+    .unwrap();
+
+    let sources = book.sources("src/typst-src");
+    let wide = sources.iter().find(|s| s.contains("#wide")).unwrap();
+    let narrow = sources.iter().find(|s| s.contains("#narrow")).unwrap();
+    // After the preamble, so it overrides its width
+    assert!(wide.ends_with("#set page(width: 900pt)\n#wide\n"), "{wide}");
+    assert!(!narrow.contains("900pt"), "{narrow}");
+}
+
+#[test]
+fn every_page_gets_an_image() {
+    let book = TestBook::new("pages", "");
+    let contents = book
+        .run(&[(
+            "chapter.md",
+            "```typ\n= One\n#pagebreak()\n= Two\n```\n\n```typ\n= Single\n```\n",
+        )])
+        .unwrap();
+
+    let images = images(&contents[0]);
+    assert_eq!(images.len(), 3, "{}", contents[0]);
+    let hash = images[0].strip_suffix("-1.svg").unwrap();
+    assert_eq!(images[1], format!("{hash}-2.svg"));
+    assert!(images[2].ends_with("-1.svg"));
+    assert_eq!(contents[0].matches("<img ").count(), 3);
+}
+
+#[test]
+fn images_have_their_size_and_lazy_loading() {
+    let book = TestBook::new("img-attrs", "lazy_images = true");
+    let contents = book.run(&[("chapter.md", "```typ\n= One\n```\n")]).unwrap();
+
+    let img = &contents[0][contents[0].find("<img ").unwrap()..];
+    let img = &img[..img.find('>').unwrap()];
+    // The view box of the stub is 15pt by 30pt
+    assert!(img.contains(r#" width="20" height="40""#), "{img}");
+    assert!(img.contains(r#" loading="lazy""#), "{img}");
+}
+
+#[test]
+fn package_paths_are_passed() {
+    let block = [(
+        "chapter.md",
+        "```typ\n#import \"@preview/cetz:0.2.2\"\n```\n",
+    )];
+    let book = TestBook::new(
+        "package-paths",
+        r#"
+        package_path = "packages"
+        package_cache_path = "cache"
+        "#,
+    );
+    book.run(&block).unwrap();
+    let compile = &book.compiles()[0];
+    let root = book.root.display();
+    assert_eq!(
+        arg(compile, "--package-path"),
+        Some(format!("{root}/packages").as_str())
+    );
+    assert_eq!(
+        arg(compile, "--package-cache-path"),
+        Some(format!("{root}/cache").as_str())
+    );
+
+    // Typst's own directories otherwise
+    let book = TestBook::new("no-package-paths", "");
+    book.run(&block).unwrap();
+    let compile = &book.compiles()[0];
+    assert_eq!(arg(compile, "--package-path"), None);
+    assert_eq!(arg(compile, "--package-cache-path"), None);
+}
+
+/// A block reading a file next to its chapter and one from the book source
+const ASSETS_BLOCK: &str =
+    "```typ\n#image(\"../dot.svg\", width: 1cm)\n#read(\"/data.txt\")\n```\n";
+
+#[test]
+fn assets_render_the_block_again_when_changed() {
+    let book = TestBook::new("assets", "");
+    book.write("src/part/dot.svg", include_str!("fixtures/dot.svg"));
+    book.write("src/data.txt", "1");
+    let chapter = [("part/chapter.md", ASSETS_BLOCK)];
+    let first = book.run(&chapter).unwrap();
+    book.run(&chapter).unwrap();
+    assert_eq!(book.compiles().len(), 1);
+
+    book.write("src/data.txt", "2");
+    let changed = book.run(&chapter).unwrap();
+    assert_eq!(book.compiles().len(), 2);
+    assert_ne!(images(&first[0]), images(&changed[0]));
+}
+
+#[test]
+fn assets_are_found_by_the_typst_cli() {
+    let book = TestBook::new("assets-cli", "");
+    book.write("src/part/dot.svg", include_str!("fixtures/dot.svg"));
+    book.write("src/data.txt", "Data");
+    book.run(&[("part/chapter.md", ASSETS_BLOCK)]).unwrap();
+
+    // "../dot.svg" is resolved from the source in part/typst-src, "/data.txt" from the root
+    let compiles = book.compiles();
+    let src = book.src().display().to_string();
+    assert_eq!(arg(&compiles[0], "--root"), Some(src.as_str()));
+    let source = format!("{src}/part/typst-src/");
+    assert!(compiles[0][0].starts_with(&source), "{compiles:?}");
+    let sources = book.sources("src/part/typst-src");
+    assert!(sources[0].contains("#image(\"../dot.svg\""), "{sources:?}");
+}
+
+#[cfg(feature = "library")]
+#[test]
+fn assets_are_found_by_typst() {
+    let book = TestBook::new(
+        "assets-library",
+        "backend = \"library\"\nfail_on_error = true",
+    );
+    book.write("src/part/dot.svg", include_str!("fixtures/dot.svg"));
+    book.write("src/data.txt", "Data");
+    let contents = book.run(&[("part/chapter.md", ASSETS_BLOCK)]).unwrap();
+
+    let image = book
+        .src()
+        .join("part/typst-img")
+        .join(images(&contents[0])[0]);
+    let svg = std::fs::read_to_string(image).unwrap();
+    assert!(svg.contains("<image"), "{svg}");
+
+    std::fs::remove_file(book.src().join("data.txt")).unwrap();
+    let missing = book.run(&[("part/chapter.md", ASSETS_BLOCK)]);
+    assert!(missing.is_err());
+}
+
+#[test]
+fn identical_blocks_compile_once() {
+    let book = TestBook::new("identical-blocks", "");
+    let block = "```typ\n// STUB-SLEEP\n#same\n```\n";
+    let twice = format!("{block}\n{block}");
+    let contents = book.run(&[("a.md", &twice), ("b.md", block)]).unwrap();
+
+    assert_eq!(book.compiles().len(), 1, "{:#?}", book.log());
+    let image = images(&contents[0])[0];
+    assert_eq!(images(&contents[0]), [image, image]);
+    assert_eq!(images(&contents[1]), [image]);
+    assert!(book.src().join("typst-img").join(image).is_file());
+
+    // Cached by the next build
+    book.run(&[("a.md", block)]).unwrap();
+    assert_eq!(book.compiles().len(), 1);
+}
+
+#[test]
+fn only_configured_languages_are_typst() {
+    let book = TestBook::new("languages", r#"languages = ["typ", "typst-example"]"#);
+    let contents = book
+        .run(&[(
+            "chapter.md",
+            "```typst-example\n#configured\n```\n\n```typst\n#not_configured\n```\n",
+        )])
+        .unwrap();
+    let content = &contents[0];
+
+    // Only the configured block is highlighted and rendered
+    assert_eq!(
+        content
+            .matches(r#"<code class="language-typ hljs">"#)
+            .count(),
+        1
+    );
+    assert_eq!(images(content).len(), 1, "{content}");
+    assert_eq!(book.compiles().len(), 1);
+    // The other one is left to mdBook, like any other language
+    assert!(content.contains("typst\n#not_configured\n"), "{content}");
+}
+
+#[test]
+fn font_paths_are_passed() {
+    // Relative to the book root, or absolute
+    let shared = Path::new(env!("CARGO_TARGET_TMPDIR")).join("shared-fonts");
+    std::fs::create_dir_all(&shared).unwrap();
+    let shared = shared.display().to_string();
+    let book = TestBook::new(
+        "font-paths",
+        &format!(r#"font_paths = ["fonts", "missing", "{shared}"]"#),
+    );
+    std::fs::create_dir_all(book.root.join("fonts")).unwrap();
+    book.run(&[("chapter.md", "```typ\n#x\n```\n")]).unwrap();
+
+    let compile = &book.compiles()[0];
+    let font_paths: Vec<_> = compile
+        .iter()
+        .zip(&compile[1..])
+        .filter(|(flag, _)| *flag == "--font-path")
+        .map(|(_, path)| path.as_str())
+        .collect();
+    // Missing directories are skipped
+    let fonts = book.root.join("fonts").display().to_string();
+    assert_eq!(font_paths, [fonts.as_str(), shared.as_str()]);
+}
+
+#[test]
+fn fonts_of_the_book_source_by_default() {
+    let book = TestBook::new("default-fonts", "");
+    book.run(&[("chapter.md", "```typ\n#x\n```\n")]).unwrap();
+    assert_eq!(arg(&book.compiles()[0], "--font-path"), None);
+
+    std::fs::create_dir_all(book.src().join("fonts")).unwrap();
+    book.run(&[("chapter.md", "```typ\n#y\n```\n")]).unwrap();
+    let fonts = book.src().join("fonts").display().to_string();
+    assert_eq!(
+        arg(&book.compiles()[1], "--font-path"),
+        Some(fonts.as_str())
+    );
+}
+
+#[cfg(feature = "library")]
+#[test]
+fn queries_need_typst_with_the_library_backend() {
+    let mut book = TestBook::new("library-query", "backend = \"library\"");
+    book.typst_path = book.root.join("no-typst");
+    let block = "```typ\n#x\n```\n";
+    book.run(&[("chapter.md", block)]).unwrap();
+
+    let query = "```typ,query=<data>\n#metadata(1) <data>\n```\n";
+    let err = book
+        .run(&[("chapter.md", &format!("{query}\n{query}"))])
+        .unwrap_err()
+        .to_string();
+    assert!(
+        err.starts_with("Blocks with query= need the Typst CLI"),
+        "{err}"
+    );
+}
+
+#[cfg(feature = "remote")]
+#[test]
+fn queries_need_typst_with_remote_url() {
+    let mut book = TestBook::new("remote-query", "remote_url = \"http://127.0.0.1:9/\"");
+    book.typst_path = book.root.join("no-typst");
+    let query = "```typ,query=<data>\n#metadata(1) <data>\n```\n";
+    let err = book.run(&[("chapter.md", query)]).unwrap_err().to_string();
+    assert!(
+        err.starts_with("Blocks with query= need the Typst CLI"),
+        "{err}"
+    );
+}
+
+#[cfg(feature = "remote")]
+#[test]
+fn remote_url_rejects_what_it_cant_render() {
+    let book = TestBook::new("remote-pages", "remote_url = \"http://127.0.0.1:9/\"");
+    let err = book
+        .run(&[("chapter.md", "```typ,pages=2\n#x\n```\n")])
+        .unwrap_err()
+        .to_string();
+    assert!(
+        err.starts_with("pages= doesn't work with remote_url"),
+        "{err}"
+    );
+
+    let book = TestBook::new(
+        "remote-png",
+        "remote_url = \"http://127.0.0.1:9/\"\nformat = \"png\"",
+    );
+    let err = book.run(&[]).unwrap_err().to_string();
+    assert!(
+        err.contains("remote_url only work with SVG images"),
+        "{err}"
+    );
+}
+
+#[test]
+fn queries_run_again_when_their_data_changes() {
+    let book = TestBook::new("query-data", "");
+    book.write("src/data.json", "[1]");
+    let chapter = [(
+        "chapter.md",
+        "```typ,query=<data>\n#metadata(json(\"/data.json\")) <data>\n```\n",
+    )];
+    let queries = || {
+        book.log()
+            .iter()
+            .filter(|l| l.starts_with("query "))
+            .count()
+    };
+
+    book.run(&chapter).unwrap();
+    book.run(&chapter).unwrap();
+    assert_eq!(queries(), 1);
+
+    book.write("src/data.json", "[2]");
+    book.run(&chapter).unwrap();
+    assert_eq!(queries(), 2);
+}
+
+#[test]
+fn unwritable_fragments_dir_is_an_error() {
+    let book = TestBook::new("fragments-error", "fragments_dir = \"fragments\"");
+    book.write("fragments", "Not a directory");
+    let err = book
+        .run(&[("chapter.md", "```typ\n#x\n```\n")])
+        .unwrap_err()
+        .to_string();
+    assert!(err.starts_with("Can't write fragment"), "{err}");
+}
+
+#[test]
+fn unwritable_sources_dir_is_an_error() {
+    let book = TestBook::new("sources-error", "");
+    book.write("src/typst-src", "Not a directory");
+    let err = book
+        .run(&[("chapter.md", "```typ\n#x\n```\n")])
+        .unwrap_err()
+        .to_string();
+    assert!(err.starts_with("Can't write"), "{err}");
+}